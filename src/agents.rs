@@ -1,19 +1,80 @@
 use async_trait::async_trait;
-use futures_util::Stream;
+use futures::{channel::oneshot, stream::BoxStream};
 
 mod intent_detector;
 mod thought_action_agent;
 
+pub(crate) use intent_detector::LowConfidenceFallback;
 pub use thought_action_agent::ThoughtActionAgent;
+pub(crate) use thought_action_agent::{INTENT_CONVERSATION, INTENT_INFORMATION_RETRIEVAL};
 
-use crate::server::MessageChannel;
+use crate::tools::Source;
+
+/// Everything produced by an agent over a single turn of the conversation.
+#[derive(Debug, Clone, Default)]
+pub struct TurnResult {
+    pub response: String,
+    pub action: String,
+    pub action_input: String,
+    pub tool_output: String,
+    pub sources: Vec<Source>,
+}
+
+/// Something that happened while an agent was working on a turn, in the order it happened.
+#[derive(Debug)]
+pub enum AgentEvent {
+    Thought(String),
+    Action {
+        action: String,
+        input: String,
+    },
+
+    /// The tool running `action` with `input` wants to change something outside the conversation, as `description` says,
+    /// and will not continue the turn until the user has answered through `confirmation`.
+    ConfirmationRequest {
+        action: String,
+        input: String,
+        description: String,
+        confirmation: Confirmation,
+    },
+    ToolProgress(String),
+    ToolOutput(String),
+
+    /// Where the tool output came from, numbered from 1 in the order given, as cited in the response.
+    /// Only sent if there are any, after the response.
+    Sources(Vec<Source>),
+    ResponseDelta(String),
+
+    /// Always the final event of a turn.
+    Done(TurnResult),
+}
+
+/// The user's answer to an [`AgentEvent::ConfirmationRequest`].
+/// Dropping this without answering counts as a denial.
+#[derive(Debug)]
+pub struct Confirmation {
+    sender: oneshot::Sender<bool>,
+}
+
+impl Confirmation {
+    /// Returns the confirmation, along with the receiver that will get the user's answer.
+    pub fn new() -> (Self, oneshot::Receiver<bool>) {
+        let (sender, receiver) = oneshot::channel();
+        (Self { sender }, receiver)
+    }
+
+    pub fn respond(self, approved: bool) {
+        // The agent going away means the turn was abandoned, so there is nobody left to tell.
+        let _ = self.sender.send(approved);
+    }
+}
 
 #[async_trait]
 pub trait Agent {
-    async fn get_response(&mut self, message: &str) -> String;
-    async fn get_response_stream(
-        &mut self,
-        message: &str,
-        ui_channel: &mut (dyn MessageChannel + Send + Sync),
-    ) -> Box<dyn Stream<Item = Option<String>> + Unpin + Send>;
+    /// Runs a turn and returns its result once it is over.
+    /// There is nobody to ask for confirmation, so every action that needs it is denied.
+    async fn get_response(&mut self, message: &str) -> TurnResult;
+
+    /// Runs a turn, yielding its events as they happen. The stream ends after [`AgentEvent::Done`].
+    fn get_response_stream<'a>(&'a mut self, message: &'a str) -> BoxStream<'a, AgentEvent>;
 }
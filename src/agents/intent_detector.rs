@@ -1,3 +1,5 @@
+use log::warn;
+use ordered_float::OrderedFloat;
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -5,6 +7,11 @@ use crate::{
     model_client::{GuidanceRequestBuilder, ModelClient},
 };
 
+/// How sure the model can say it is of the intent it chose, and the probability each stands for.
+/// Only used when the server doesn't report logprobs, which say it more precisely.
+const CONFIDENCE_LEVELS: [(&str, f32); 3] =
+    [("certain", 0.95), ("fairly sure", 0.75), ("unsure", 0.4)];
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct Intent {
     name: String,
@@ -22,15 +29,103 @@ impl Intent {
     pub(crate) fn name(&self) -> &str {
         self.name.as_ref()
     }
+}
+
+/// What to do when the most likely intent is below the confidence threshold.
+#[derive(Debug, Clone)]
+pub(crate) enum LowConfidenceFallback {
+    /// Proceed as if the user had expressed the named intent.
+    DefaultIntent(String),
+
+    /// Ask the user a clarifying question instead of guessing.
+    Clarify,
+}
+
+/// The outcome of applying the confidence threshold to an [`IntentDistribution`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum IntentDecision {
+    Intent(String),
+    Clarify,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IntentScore {
+    name: String,
+    probability: f32,
+}
+
+impl IntentScore {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn probability(&self) -> f32 {
+        self.probability
+    }
+}
+
+/// Every valid intent with its probability, ranked from most to least likely.
+#[derive(Debug, Clone)]
+pub(crate) struct IntentDistribution {
+    ranked: Vec<IntentScore>,
+}
+
+impl IntentDistribution {
+    /// Builds a distribution from per-intent log probabilities, normalizing them with a softmax
+    /// so the probabilities sum to one across the valid intents.
+    fn from_logprobs<'a>(logprobs: impl IntoIterator<Item = (&'a str, f32)>) -> Self {
+        let logprobs: Vec<(&str, f32)> = logprobs.into_iter().collect();
+
+        let max = logprobs
+            .iter()
+            .map(|(_, logprob)| *logprob)
+            .fold(f32::NEG_INFINITY, f32::max);
+
+        let total: f32 = logprobs.iter().map(|(_, l)| (l - max).exp()).sum();
+
+        let mut ranked: Vec<IntentScore> = logprobs
+            .into_iter()
+            .map(|(name, logprob)| IntentScore {
+                name: name.to_owned(),
+                probability: (logprob - max).exp() / total,
+            })
+            .collect();
+
+        ranked.sort_by_key(|score| -OrderedFloat(score.probability));
+
+        Self { ranked }
+    }
+
+    /// A distribution of only the intent chosen, with the probability the model gave for it in words.
+    /// Used when the server did not report logprobs for the select.
+    fn stated(name: impl Into<String>, probability: f32) -> Self {
+        Self {
+            ranked: vec![IntentScore {
+                name: name.into(),
+                probability,
+            }],
+        }
+    }
+
+    pub(crate) fn ranked(&self) -> &[IntentScore] {
+        &self.ranked
+    }
 
-    pub(crate) fn description(&self) -> &str {
-        self.description.as_ref()
+    pub(crate) fn top(&self) -> Option<&IntentScore> {
+        self.ranked.first()
+    }
+
+    /// The probability of the most likely intent.
+    pub(crate) fn confidence(&self) -> f32 {
+        self.top().map_or(0.0, IntentScore::probability)
     }
 }
 
 pub(crate) struct IntentDetector {
     valid_intents: Vec<Intent>,
     prompt: String,
+    confidence_threshold: f32,
+    fallback: LowConfidenceFallback,
 }
 
 impl IntentDetector {
@@ -38,14 +133,28 @@ impl IntentDetector {
         Self {
             valid_intents,
             prompt,
+            confidence_threshold: 0.0,
+            fallback: LowConfidenceFallback::Clarify,
         }
     }
 
+    /// Below `threshold`, [`IntentDetector::decide`] will apply `fallback` instead of trusting the top intent.
+    pub(crate) fn with_confidence_threshold(
+        mut self,
+        threshold: f32,
+        fallback: LowConfidenceFallback,
+    ) -> Self {
+        self.confidence_threshold = threshold;
+        self.fallback = fallback;
+
+        self
+    }
+
     pub async fn detect_intent(
         &self,
         model_client: &(dyn ModelClient + Send + Sync),
         conversation: &Conversation,
-    ) -> String {
+    ) -> IntentDistribution {
         let history = conversation.build_history();
 
         let prompt = self.prompt.replace("{{history}}", &history);
@@ -54,13 +163,125 @@ impl IntentDetector {
 
         let intent_names: Vec<&str> = self.valid_intents.iter().map(Intent::name).collect();
 
+        let confidence_levels: Vec<&str> =
+            CONFIDENCE_LEVELS.iter().map(|(level, _)| *level).collect();
+
         let request = GuidanceRequestBuilder::new(prompt)
             .with_object_parameter("intents", &intent_objects)
             .with_parameter_list("intent_names", &intent_names)
+            .with_parameter_list("confidence_levels", &confidence_levels)
             .build();
 
         let guidance_result = model_client.request_guidance(&request).await;
-        let selected_intent = guidance_result.expect_variable("intent");
-        selected_intent.into()
+        let selected_intent = guidance_result.expect_variable("intent").trim();
+
+        let intent_logprobs: Vec<(&str, f32)> = guidance_result
+            .logprobs("intent_logprobs")
+            .map(|logprobs| {
+                intent_names
+                    .iter()
+                    .filter_map(|&name| logprobs.get(name).map(|&logprob| (name, logprob)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if !intent_logprobs.is_empty() {
+            return IntentDistribution::from_logprobs(intent_logprobs);
+        }
+
+        // Without logprobs, the model's own word for how sure it is will have to do:
+        let stated = guidance_result.variable("confidence").unwrap_or_default();
+        let probability = stated_confidence(stated).unwrap_or_else(|| {
+            warn!("Got neither logprobs nor a known confidence ('{stated}') for the intent; trusting it");
+            1.0
+        });
+
+        IntentDistribution::stated(selected_intent, probability)
+    }
+
+    pub(crate) fn decide(&self, distribution: &IntentDistribution) -> IntentDecision {
+        match distribution.top() {
+            Some(top) if top.probability() >= self.confidence_threshold => {
+                IntentDecision::Intent(top.name().to_owned())
+            }
+            _ => match &self.fallback {
+                LowConfidenceFallback::DefaultIntent(name) => IntentDecision::Intent(name.clone()),
+                LowConfidenceFallback::Clarify => IntentDecision::Clarify,
+            },
+        }
+    }
+}
+
+/// The probability a confidence level the model chose stands for, if it is one of [`CONFIDENCE_LEVELS`].
+fn stated_confidence(level: &str) -> Option<f32> {
+    CONFIDENCE_LEVELS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(level.trim()))
+        .map(|(_, probability)| *probability)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(distribution: &IntentDistribution) -> Vec<&str> {
+        distribution
+            .ranked()
+            .iter()
+            .map(IntentScore::name)
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_logprobs_into_a_ranked_distribution() {
+        let distribution = IntentDistribution::from_logprobs([
+            ("conversation", 2.0_f32.ln()),
+            ("information_retrieval", 6.0_f32.ln()),
+            ("home_automation", 2.0_f32.ln()),
+        ]);
+
+        assert_eq!(names(&distribution)[0], "information_retrieval");
+
+        let total: f32 = distribution
+            .ranked()
+            .iter()
+            .map(IntentScore::probability)
+            .sum();
+        assert!((total - 1.0).abs() < 1e-6);
+        assert!((distribution.confidence() - 0.6).abs() < 1e-6);
+        assert!((distribution.ranked()[1].probability() - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn falls_back_below_the_threshold() {
+        let detector = |fallback| {
+            IntentDetector::new(Vec::new(), String::new()).with_confidence_threshold(0.6, fallback)
+        };
+        let sure = IntentDistribution::from_logprobs([("conversation", 0.0), ("other", -3.0)]);
+        let unsure = IntentDistribution::from_logprobs([("conversation", 0.0), ("other", -0.1)]);
+
+        let clarifying = detector(LowConfidenceFallback::Clarify);
+        assert_eq!(
+            clarifying.decide(&sure),
+            IntentDecision::Intent(String::from("conversation"))
+        );
+        assert_eq!(clarifying.decide(&unsure), IntentDecision::Clarify);
+
+        let defaulting = detector(LowConfidenceFallback::DefaultIntent(String::from("search")));
+        assert_eq!(
+            defaulting.decide(&unsure),
+            IntentDecision::Intent(String::from("search"))
+        );
+    }
+
+    #[test]
+    fn reads_the_stated_confidence() {
+        assert_eq!(stated_confidence(" Certain"), Some(0.95));
+        assert_eq!(stated_confidence("unsure"), Some(0.4));
+        assert_eq!(stated_confidence("maybe"), None);
+
+        let unsure =
+            IntentDistribution::stated("conversation", stated_confidence("unsure").unwrap());
+        assert!(unsure.confidence() < 0.6);
     }
 }
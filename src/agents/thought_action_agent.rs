@@ -1,59 +1,233 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
 use async_trait::async_trait;
-use futures_util::{Stream, StreamExt};
-use log::{info, warn};
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    future,
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use log::{debug, info, warn};
+use ordered_float::OrderedFloat;
 
 use crate::{
+    config,
     conversation::{ChatMessage, Conversation},
     load_prompt_text,
+    memory::{
+        decay, reinforce, unix_timestamp, MemoryScope, MemorySource, MemoryStore, MemoryWriter,
+    },
     model_client::{
         GuidanceRequestBuilder, GuidanceResponse, MemoryGetRequest, MemoryStoreRequest, ModelClient,
     },
-    server::{MessageChannel, MessageToClient},
-    tools::{web_search::WebSearch, Tool},
+    tools::{home_automation::HomeAutomation, noop::Noop, Confirmer, Tool, ToolOutput},
 };
 
 use super::{
-    intent_detector::{Intent, IntentDetector},
-    Agent,
+    intent_detector::{Intent, IntentDecision, IntentDetector},
+    Agent, AgentEvent, Confirmation, TurnResult,
 };
 
 pub struct ThoughtActionAgent {
     model_client: Box<dyn ModelClient + Send + Sync>,
+    memory_store: Arc<dyn MemoryStore + Send + Sync>,
+    memory_writer: MemoryWriter,
+    memory_scope: MemoryScope,
+    tools: Vec<Arc<dyn Tool + Send + Sync>>,
     conversation: Conversation,
 }
 
 impl ThoughtActionAgent {
-    pub fn new(model_client: Box<dyn ModelClient + Send + Sync>) -> Self {
+    pub fn new(
+        model_client: Box<dyn ModelClient + Send + Sync>,
+        memory_store: Arc<dyn MemoryStore + Send + Sync>,
+        memory_writer: MemoryWriter,
+        memory_scope: MemoryScope,
+    ) -> Self {
         Self {
             model_client,
+            memory_store,
+            memory_writer,
+            memory_scope,
+            tools: vec![Arc::new(Noop)],
             conversation: Conversation::new(),
         }
     }
 
-    fn intent_detector() -> IntentDetector {
+    /// Makes `tool` available to the model, in addition to the default `NONE`.
+    pub fn with_tool(mut self, tool: Arc<dyn Tool + Send + Sync>) -> Self {
+        self.tools.push(tool);
+        self
+    }
+
+    fn tool(&self, name: &str) -> Option<Arc<dyn Tool + Send + Sync>> {
+        self.tools.iter().find(|tool| tool.name() == name).cloned()
+    }
+
+    fn intent_detector(&self) -> IntentDetector {
         let prompt = load_prompt_text("intent_detection.txt");
-        IntentDetector::new(
-            vec![
-                Intent::new("information_retrieval", "The user intends to retrieve information from some knowledge store, such as the web."),
-            ],
-            prompt,
+
+        let mut intents = vec![
+            Intent::new(INTENT_INFORMATION_RETRIEVAL, "The user intends to retrieve information from some knowledge store, such as the web."),
+            Intent::new(INTENT_CONVERSATION, "The user is making small talk, or replying in a way that needs no outside information."),
+        ];
+
+        if self.tool(HomeAutomation::NAME).is_some() {
+            intents.push(Intent::new(INTENT_HOME_AUTOMATION, "The user wants to check on or control a device in their home, such as lights or switches."));
+        }
+
+        let intent_config = config::get().intent();
+
+        IntentDetector::new(intents, prompt).with_confidence_threshold(
+            intent_config.confidence_threshold,
+            intent_config.fallback.clone(),
         )
     }
+
+    /// Looks up past exchanges relevant to `message`, and renders them as a block for the prompt.
+    /// Memories that are not relevant enough once decayed by age, or that are already visible in the conversation, are skipped.
+    /// Recalled memories are reinforced, so they decay more slowly.
+    /// The block is plain text, for the system prompt. Returns an empty string if nothing relevant was found.
+    async fn recall_memories(&self, message: &str) -> String {
+        let request = MemoryGetRequest {
+            query: message.to_owned(),
+            filter: self.memory_scope.user_filter(),
+        };
+
+        let response = self.memory_store.query(&request).await;
+
+        // Turns are remembered just as they are written here, so a memory of one is the same text:
+        let visible: HashSet<String> = self
+            .conversation
+            .turns()
+            .map(Conversation::messages_to_string)
+            .collect();
+
+        let now = unix_timestamp();
+        let mut recalled = Vec::<(f32, &str, &str, HashMap<String, String>)>::new();
+
+        for (((id, document), distance), metadata) in response
+            .ids
+            .iter()
+            .zip(&response.documents)
+            .zip(&response.distances)
+            .zip(&response.metadatas)
+        {
+            let metadata: HashMap<String, String> =
+                serde_json::from_str(metadata).unwrap_or_default();
+            let relevance = (1.0 - distance) * decay(&metadata, now);
+
+            if relevance < MIN_MEMORY_RELEVANCE {
+                debug!("Skipping memory with relevance {relevance}: {document}");
+                continue;
+            }
+
+            if visible.contains(document) || recalled.iter().any(|(_, _, d, _)| d == document) {
+                debug!("Skipping memory already in the conversation: {document}");
+                continue;
+            }
+
+            recalled.push((relevance, id, document, metadata));
+        }
+
+        info!("Recalled {} memories", recalled.len());
+
+        if recalled.is_empty() {
+            return String::new();
+        }
+
+        recalled.sort_by_key(|(relevance, ..)| -OrderedFloat(*relevance));
+
+        let memories = recalled
+            .iter()
+            .map(|(_, _, document, _)| *document)
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        // Being recalled keeps a memory fresh, in a store that can update it:
+        if self.memory_store.can_manage() {
+            let mut reinforce_request = MemoryStoreRequest::new();
+            for (_, id, document, mut metadata) in recalled {
+                reinforce(&mut metadata);
+                reinforce_request.add_document(id, document, metadata);
+            }
+
+            self.memory_writer.update(reinforce_request);
+        }
+
+        load_prompt_text("memory_block.txt").replace("{{memories}}", &memories)
+    }
+
+    /// The actions the model may choose from, given the user's intent.
+    fn valid_actions(&self, intent: &str) -> Vec<&'static str> {
+        let names = self.tools.iter().map(|tool| tool.name());
+
+        match intent {
+            INTENT_CONVERSATION => vec![Noop::NAME],
+            INTENT_HOME_AUTOMATION => names
+                .filter(|&name| name == HomeAutomation::NAME || name == Noop::NAME)
+                .collect(),
+            _ => names.collect(),
+        }
+    }
+
+    /// Asks the model for a short question that would clear up what the user wants.
+    async fn clarifying_question(&self) -> String {
+        let prompt = load_prompt_text("intent_clarify.txt");
+        let prompt = prompt.replace("{{history}}", &self.conversation.build_history());
+
+        let request = GuidanceRequestBuilder::new(prompt).build();
+        let response = self.model_client.request_guidance(&request).await;
+
+        response.expect_variable("response").trim().to_owned()
+    }
 }
 
+/// Memories less relevant than this to the user's message, after decay, are not recalled.
+/// Relevance is similarity to the message, scaled down by the memory's age.
+const MIN_MEMORY_RELEVANCE: f32 = 0.5;
+
+/// How much of the conversation a tool gets to put its input in context, counting the user's latest message.
+const RECENT_MESSAGES_FOR_TOOLS: usize = 6;
+
+pub(crate) const INTENT_INFORMATION_RETRIEVAL: &str = "information_retrieval";
+pub(crate) const INTENT_CONVERSATION: &str = "conversation";
+
+const INTENT_HOME_AUTOMATION: &str = "home_automation";
+
 #[async_trait]
 impl Agent for ThoughtActionAgent {
-    async fn get_response(&mut self, _message: &str) -> String {
-        todo!()
+    async fn get_response(&mut self, message: &str) -> TurnResult {
+        self.run_turn(message, None).await
     }
 
-    async fn get_response_stream(
+    fn get_response_stream<'a>(&'a mut self, message: &'a str) -> BoxStream<'a, AgentEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+
+        let turn = async move {
+            let result = self.run_turn(message, Some(&sender)).await;
+            emit(Some(&sender), AgentEvent::Done(result));
+        };
+
+        // The turn itself yields nothing; polling it alongside the receiver is what drives it.
+        // Once it completes, the sender is dropped and the receiver ends the stream.
+        let turn = turn.into_stream().filter_map(|()| future::ready(None));
+
+        stream::select(receiver, turn).boxed()
+    }
+}
+
+impl ThoughtActionAgent {
+    /// Runs a full thought/action/tool/response turn.
+    /// If an `events` sender is given, progress and the response are sent to it as they arrive.
+    async fn run_turn(
         &mut self,
         message: &str,
-        ui_channel: &mut (dyn MessageChannel + Send + Sync),
-    ) -> Box<dyn Stream<Item = Option<String>> + Unpin + Send> {
+        events: Option<&UnboundedSender<AgentEvent>>,
+    ) -> TurnResult {
         // let prompt_preamble = load_prompt_text("guider_preamble.txt");
         warn!("Loading llama2chat preamble");
         let prompt_preamble = load_prompt_text("guider_preamble_llama2chat.txt");
@@ -62,10 +236,45 @@ impl Agent for ThoughtActionAgent {
         self.conversation
             .add_message(ChatMessage::User(message.into()));
 
+        let valid_actions = {
+            let intent_detector = self.intent_detector();
+            let distribution = intent_detector
+                .detect_intent(self.model_client.as_ref(), &self.conversation)
+                .await;
+
+            info!(
+                "Detected intent distribution (confidence {}): {:?}",
+                distribution.confidence(),
+                distribution.ranked()
+            );
+
+            match intent_detector.decide(&distribution) {
+                IntentDecision::Intent(intent) => self.valid_actions(&intent),
+                IntentDecision::Clarify => {
+                    let question = self.clarifying_question().await;
+                    info!("Intent unclear; asking: {question}");
+
+                    emit(events, AgentEvent::ResponseDelta(question.clone()));
+
+                    self.conversation
+                        .add_message(ChatMessage::Assistant(question.clone()));
+
+                    return TurnResult {
+                        response: question,
+                        action: String::from("NONE"),
+                        ..Default::default()
+                    };
+                }
+            }
+        };
+
         // Hack: we need to manually replace {{history}} first, because that value
         // is itself templated, and guidance only performs template replacement once
         let prompt_chat: String = prompt_chat.replace("{{preamble}}", &prompt_preamble);
 
+        let memory = self.recall_memories(message).await;
+        let prompt_chat: String = prompt_chat.replace("{{memory~}}", &memory);
+
         let history = self.conversation.build_history();
         let prompt_chat: String = prompt_chat.replace("{{history~}}", &history);
 
@@ -74,75 +283,93 @@ impl Agent for ThoughtActionAgent {
         // First, as the ThoughtActionAgent, we get the thought/action output:
         let request = GuidanceRequestBuilder::new(prompt_chat)
             .with_parameter("user_input", message)
-            .with_parameter_list("valid_actions", &["WEB_SEARCH", "NONE"])
+            .with_parameter_list("valid_actions", &valid_actions)
             .build();
 
         let output = self.model_client.request_guidance(&request).await;
 
-        info!("Got thought/action output:\n{:#?}", output);
-
-        // Testing memory and intent detection:
-        {
-            // let memory_request = MemoryGetRequest {
-            //     query: "hello".to_owned(),
-            // };
-            // let memory_response = self.model_client.request_memory(&memory_request).await;
-            // let intent_detector = Self::intent_detector();
-            // let intent = intent_detector
-            //     .detect_intent(self.model_client.as_ref(), &self.conversation)
-            //     .await;
-
-            // info!("Detected intent: {intent}");
-        }
+        info!("Got thought/action output:\n{output:#?}");
 
         // The first response will have thought, action, and action_input filled out.
         let action = output.expect_variable("action").trim();
         let action_input = output.expect_variable("action_input").trim();
 
+        if let Some(thought) = output.variable("thought_action") {
+            emit(events, AgentEvent::Thought(thought.trim().to_owned()));
+        }
+
+        emit(
+            events,
+            AgentEvent::Action {
+                action: action.to_owned(),
+                input: action_input.to_owned(),
+            },
+        );
+
         // Now we execute the tool selected by the model:
-        let tool_output = {
-            let tool = WebSearch;
-
-            if action == "NONE" {
-                String::new()
-            } else {
-                ui_channel
-                    .send(MessageToClient::new(
-                        String::new(),
-                        format!("Searching: {action_input}"),
-                        0,
-                    ))
+        let tool_output = match self.tool(action) {
+            Some(tool) if action != Noop::NAME => {
+                emit(
+                    events,
+                    AgentEvent::ToolProgress(tool.progress_message(action_input)),
+                );
+
+                let messages = self.conversation.messages();
+                let recent_messages =
+                    &messages[messages.len().saturating_sub(RECENT_MESSAGES_FOR_TOOLS)..];
+
+                let confirmer = EventConfirmer {
+                    action,
+                    input: action_input,
+                    may_ask: tool.has_side_effects(),
+                    events,
+                    decisions: Mutex::new(Vec::new()),
+                };
+
+                let tool_output = tool
+                    .get_output(
+                        action_input,
+                        recent_messages,
+                        self.model_client.as_ref(),
+                        &confirmer,
+                    )
                     .await;
 
-                tool.get_output(action_input, action_input, self.model_client.as_ref())
-                    .await
+                // What the user allowed or refused stays in the conversation, so later turns know:
+                for decision in confirmer.into_decisions() {
+                    self.conversation.add_message(ChatMessage::System(decision));
+                }
+
+                tool_output
+            }
+            Some(_) => ToolOutput::default(),
+            None => {
+                warn!("Model chose unknown action '{action}'");
+                ToolOutput::default()
             }
         };
 
         let response = {
             let ongoing_chat = output.text();
             let request = GuidanceRequestBuilder::new(ongoing_chat)
-                .with_parameter("output", tool_output.clone())
+                .with_parameter("output", tool_output.text())
                 .build();
 
             let mut complete_response = GuidanceResponse::new();
             let mut response_stream = self.model_client.request_guidance_stream(&request);
-            let mut stream_count = 0;
+            let mut is_first_delta = true;
 
             while let Some(Some(delta)) = response_stream.next().await {
                 if let Some(response_delta) = delta.variable("response") {
                     if !response_delta.is_empty() {
-                        let response_delta = if stream_count == 0 {
+                        let response_delta = if is_first_delta {
                             response_delta.trim_start().to_owned()
                         } else {
                             response_delta.to_owned()
                         };
 
-                        let to_client =
-                            MessageToClient::new(String::new(), response_delta, stream_count);
-
-                        ui_channel.send(to_client).await;
-                        stream_count += 1;
+                        emit(events, AgentEvent::ResponseDelta(response_delta));
+                        is_first_delta = false;
                     }
                 }
 
@@ -152,43 +379,134 @@ impl Agent for ThoughtActionAgent {
             complete_response
         };
 
-        if !tool_output.is_empty() {
-            ui_channel
-                .send(MessageToClient::new(
-                    String::from("ToolInfo"),
-                    tool_output,
-                    0,
-                ))
-                .await;
+        if !tool_output.sources().is_empty() {
+            emit(events, AgentEvent::Sources(tool_output.sources().to_vec()));
+        }
+
+        if !tool_output.text().is_empty() {
+            emit(
+                events,
+                AgentEvent::ToolOutput(tool_output.text().to_owned()),
+            );
         }
 
         let response_text = response.expect_variable("response");
-        info!(
-            "\n\n-----------------\nReponse is\n{:?}\n------------------\n\n",
-            response_text
-        );
+        info!("\n\n-----------------\nReponse is\n{response_text:?}\n------------------\n\n");
 
         let assistant_message = build_assistant_chat_message(action, action_input, response_text);
-        info!("Added assistant message:\n{:?}", assistant_message);
+        info!("Added assistant message:\n{assistant_message:?}");
         self.conversation.add_message(assistant_message);
 
         // Store user and assistant output for just this turn as a document
         {
-            let last_two_range = self.conversation.messages().len() - 2..;
-            let last_two_messages = &self.conversation.messages()[last_two_range];
+            let turn_messages = self
+                .conversation
+                .turns()
+                .last()
+                .expect("The user's message was added at the start of the turn");
 
-            let messages_stringified = Conversation::messages_to_string(last_two_messages);
+            let messages_stringified = Conversation::messages_to_string(turn_messages);
 
             let mut memory_request = MemoryStoreRequest::new();
 
-            // Empty ID value is ok, server will generate a guid for it:
-            memory_request.add_document("", messages_stringified, HashMap::new());
+            // Empty ID value is ok, the writer will generate one for it:
+            memory_request.add_document(
+                "",
+                messages_stringified,
+                self.memory_scope.metadata(MemorySource::ChatTurn),
+            );
+
+            // Written in the background, so the next turn doesn't wait on the memory store:
+            self.memory_writer.store(memory_request);
+        }
+
+        let (tool_output, sources) = tool_output.into_parts();
 
-            self.model_client.store_memory(&memory_request).await;
+        TurnResult {
+            response: response_text.trim().to_owned(),
+            action: action.to_owned(),
+            action_input: action_input.to_owned(),
+            tool_output,
+            sources,
         }
+    }
+}
 
-        // We will return nothing, since we already sent the client everything ourselves. No need to make the session do it for us.
-        Box::new(futures::stream::empty())
+fn emit(events: Option<&UnboundedSender<AgentEvent>>, event: AgentEvent) {
+    if let Some(events) = events {
+        // The receiver going away just means nobody is listening anymore; the turn still completes.
+        let _ = events.unbounded_send(event);
+    }
+}
+
+/// Asks the user through the turn's events, on behalf of the tool running `action` with `input`, and waits for their answer.
+/// Only a tool that declares side effects `may_ask`; anything else it asks is refused.
+/// Without anyone listening for events there is nobody to ask, so the answer is always no.
+struct EventConfirmer<'a> {
+    action: &'a str,
+    input: &'a str,
+    may_ask: bool,
+    events: Option<&'a UnboundedSender<AgentEvent>>,
+
+    /// What the user was asked, and what they answered, in order, for the conversation.
+    decisions: Mutex<Vec<String>>,
+}
+
+impl EventConfirmer<'_> {
+    fn into_decisions(self) -> Vec<String> {
+        self.decisions
+            .into_inner()
+            .expect("The decisions lock is never held across a panic")
+    }
+
+    async fn ask(&self, description: &str) -> bool {
+        if !self.may_ask {
+            warn!(
+                "{} asked to confirm {description}, but doesn't declare side effects; refusing",
+                self.action
+            );
+            return false;
+        }
+
+        let Some(events) = self.events else {
+            warn!("Nobody to confirm {description} with; denying it");
+            return false;
+        };
+
+        let (confirmation, answer) = Confirmation::new();
+
+        let request = AgentEvent::ConfirmationRequest {
+            action: self.action.to_owned(),
+            input: self.input.to_owned(),
+            description: description.to_owned(),
+            confirmation,
+        };
+
+        if events.unbounded_send(request).is_err() {
+            return false;
+        }
+
+        // A dropped confirmation means the user never answered:
+        answer.await.unwrap_or(false)
+    }
+}
+
+#[async_trait]
+impl Confirmer for EventConfirmer<'_> {
+    async fn confirm(&self, description: &str) -> bool {
+        let approved = self.ask(description).await;
+
+        let decision = if approved {
+            format!("The user approved {description}.")
+        } else {
+            format!("The user did not approve {description}, so it was not done.")
+        };
+        self.decisions
+            .lock()
+            .expect("The decisions lock is never held across a panic")
+            .push(decision);
+
+        approved
     }
 }
 
@@ -199,3 +517,70 @@ fn build_assistant_chat_message(action: &str, action_input: &str, response: &str
     template = template.replace("{{response}}", response.trim());
     ChatMessage::Assistant(template)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn confirmer(
+        may_ask: bool,
+        events: Option<&UnboundedSender<AgentEvent>>,
+    ) -> EventConfirmer<'_> {
+        EventConfirmer {
+            action: "TOOL",
+            input: "input",
+            may_ask,
+            events,
+            decisions: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn records_what_the_user_answered() {
+        let (sender, mut receiver) = mpsc::unbounded();
+        let confirmer = confirmer(true, Some(&sender));
+
+        let answering = async {
+            for approved in [true, false] {
+                let Some(AgentEvent::ConfirmationRequest { confirmation, .. }) =
+                    receiver.next().await
+                else {
+                    panic!("Expected a confirmation request");
+                };
+                confirmation.respond(approved);
+            }
+        };
+        let asking = async {
+            (
+                confirmer.confirm("turning on the light").await,
+                confirmer.confirm("unlocking the door").await,
+            )
+        };
+
+        let ((), answers) = tokio::join!(answering, asking);
+
+        assert_eq!(answers, (true, false));
+        assert_eq!(
+            confirmer.into_decisions(),
+            [
+                "The user approved turning on the light.",
+                "The user did not approve unlocking the door, so it was not done.",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_tools_without_side_effects_and_turns_without_a_listener() {
+        let (sender, mut receiver) = mpsc::unbounded();
+
+        let undeclared = confirmer(false, Some(&sender));
+        assert!(!undeclared.confirm("turning on the light").await);
+        assert_eq!(undeclared.into_decisions().len(), 1);
+
+        let unheard = confirmer(true, None);
+        assert!(!unheard.confirm("turning on the light").await);
+
+        drop(sender);
+        assert!(receiver.next().await.is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, info, trace, warn};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use sha1::{Digest, Sha1};
+
+use crate::memory::{generate_id, unix_timestamp};
+
+/// Temporary files older than this were left behind by a write that never finished.
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_hours(1);
+
+/// A key/value cache kept as one JSON file per entry, so it survives restarts.
+/// Entries expire after the TTL they were written with, and are removed when next read, or by
+/// [`DiskCache::sweep_every`] if they never are. Failing to read or write the cache is
+/// never an error: it is logged, and the caller carries on as though the entry was missing.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+    expires_at: u64,
+    value: T,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The unexpired value stored under `key` in `namespace`, if any.
+    pub async fn get<T: DeserializeOwned>(&self, namespace: &str, key: &str) -> Option<T> {
+        let path = self.path(namespace, key);
+
+        let bytes = tokio::fs::read(&path).await.ok()?;
+
+        let entry = match serde_json::from_slice::<CacheEntry<T>>(&bytes) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Ignoring unreadable cache entry {}: {e}", path.display());
+                return None;
+            }
+        };
+
+        if entry.expires_at <= unix_timestamp() {
+            trace!("Cache entry expired: {namespace}/{key}");
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        }
+
+        debug!("Cache hit: {namespace}/{key}");
+        Some(entry.value)
+    }
+
+    /// Stores `value` under `key` in `namespace` for `ttl`. A zero `ttl` stores nothing.
+    pub async fn put<T: Serialize>(&self, namespace: &str, key: &str, value: &T, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+
+        let entry = CacheEntry {
+            expires_at: unix_timestamp() + ttl.as_secs(),
+            value,
+        };
+
+        let path = self.path(namespace, key);
+
+        let result = async {
+            let json = serde_json::to_vec(&entry)?;
+
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            // Write to the side and then swap in, so readers never see a partial entry.
+            // Each write gets a file of its own, so two writing the same entry at once can't mix:
+            let temp_path = path.with_extension(format!("{}.tmp", generate_id()));
+            tokio::fs::write(&temp_path, json).await?;
+            tokio::fs::rename(&temp_path, &path).await?;
+
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            warn!("Failed to write cache entry {}: {e}", path.display());
+        }
+    }
+
+    /// Removes expired entries once every `interval`, forever.
+    pub async fn sweep_every(self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+            self.sweep().await;
+        }
+    }
+
+    /// Removes every expired or unreadable entry, and whatever unfinished writes left behind.
+    async fn sweep(&self) {
+        let now = unix_timestamp();
+        let mut removed = 0;
+
+        let Ok(mut namespaces) = tokio::fs::read_dir(&self.dir).await else {
+            return;
+        };
+
+        while let Ok(Some(namespace)) = namespaces.next_entry().await {
+            let Ok(mut files) = tokio::fs::read_dir(namespace.path()).await else {
+                continue;
+            };
+
+            while let Ok(Some(file)) = files.next_entry().await {
+                let path = file.path();
+
+                if is_removable(&path, now).await && tokio::fs::remove_file(&path).await.is_ok() {
+                    removed += 1;
+                }
+            }
+        }
+
+        info!("Removed {removed} expired cache entries");
+    }
+
+    fn path(&self, namespace: &str, key: &str) -> PathBuf {
+        self.dir
+            .join(namespace)
+            .join(format!("{}.json", content_hash(key)))
+    }
+}
+
+/// True if the file at `path` is an entry that expired before `now` or can't be read,
+/// or a temporary file that is too old to still be being written.
+async fn is_removable(path: &Path, now: u64) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => tokio::fs::read(path).await.is_ok_and(|bytes| {
+            serde_json::from_slice::<CacheEntry<IgnoredAny>>(&bytes)
+                .map_or(true, |entry| entry.expires_at <= now)
+        }),
+        Some("tmp") => tokio::fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .is_ok_and(|age| age > STALE_TEMP_FILE_AGE)
+            }),
+        _ => false,
+    }
+}
+
+/// A hex digest of `text`, stable across runs and builds.
+pub fn content_hash(text: &str) -> String {
+    let digest = Sha1::digest(text.as_bytes());
+
+    digest.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
@@ -0,0 +1,906 @@
+use std::{
+    env,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    agents::{LowConfidenceFallback, INTENT_CONVERSATION, INTENT_INFORMATION_RETRIEVAL},
+    tools::web_search::{
+        FusionMethod, RerankMethod, SearchMode, DEFAULT_REWRITE_TIMEOUT_MS, DEFAULT_TIME_BUDGET_MS,
+        EMBEDDING_MODEL_MAX_TOKENS,
+    },
+};
+
+/// Read when `RAINCHAIN_CONFIG` does not name another file. It's fine for it not to exist.
+const DEFAULT_CONFIG_PATH: &str = "rainchain.json";
+
+/// Where the local memory store saves memories, unless `RAINCHAIN_MEMORY_PATH` says otherwise.
+const DEFAULT_MEMORY_PATH: &str = "memory.json";
+
+const DEFAULT_INTENT_CONFIDENCE_THRESHOLD: f32 = 0.6;
+
+const DEFAULT_SEARCH_CACHE_TTL: Duration = Duration::from_hours(24);
+const DEFAULT_PAGE_CACHE_TTL: Duration = Duration::from_hours(24);
+const DEFAULT_EMBEDDING_CACHE_TTL: Duration = Duration::from_hours(30 * 24);
+
+const DEFAULT_CHUNK_MAX_TOKENS: usize = 256;
+const DEFAULT_CHUNK_OVERLAP_TOKENS: usize = 32;
+
+const DEFAULT_LEXICAL_WEIGHT: f32 = 0.5;
+
+const DEFAULT_RERANK_CANDIDATES: usize = 20;
+
+/// Says who is fetching, rather than passing for a browser or a search engine's crawler.
+const DEFAULT_USER_AGENT: &str = concat!("rainchain/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_PER_DOMAIN_CONCURRENCY: usize = 2;
+const DEFAULT_PER_DOMAIN_DELAY_MS: u64 = 1000;
+
+/// Each sub-query is a search of its own, so questions are only split up if asked for.
+const DEFAULT_MAX_SUB_QUERIES: usize = 1;
+
+const DEFAULT_DIVERSITY: f32 = 0.3;
+const DEFAULT_DUPLICATE_SIMILARITY: f32 = 0.95;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Shown in place of credentials in `Debug` output.
+const REDACTED: &str = "<redacted>";
+
+/// Settings resolved once at startup, from environment variables and an optional JSON config file.
+/// Environment variables win over the file.
+#[derive(Debug, Clone)]
+pub struct Config {
+    prompts_dir: PathBuf,
+    memory: MemoryConfig,
+    intent: IntentConfig,
+    search: Option<SearchConfig>,
+    search_mode: SearchMode,
+    search_budget: Duration,
+    rewrite_timeout: Duration,
+    max_sub_queries: usize,
+    home_assistant: Option<HomeAssistantConfig>,
+    cache: CacheConfig,
+    chunking: ChunkingConfig,
+    ranking: RankingConfig,
+    rerank: Option<RerankConfig>,
+    selection: SelectionConfig,
+    fetch: FetchConfig,
+}
+
+/// Where memories are kept, and how they are tidied up.
+#[derive(Debug, Clone)]
+pub struct MemoryConfig {
+    /// The file the in-process memory store saves to, if memories are kept locally rather than on the model server.
+    pub local_path: Option<PathBuf>,
+
+    /// Whether consolidating memories also boils each group of duplicates down to the facts in it.
+    pub extract_facts: bool,
+}
+
+/// How sure the agent must be of what the user wants before acting on it.
+#[derive(Debug, Clone)]
+pub struct IntentConfig {
+    /// Below this probability, from 0 to 1, the most likely intent is not trusted.
+    pub confidence_threshold: f32,
+
+    /// What the agent does when it isn't sure enough.
+    pub fallback: LowConfidenceFallback,
+}
+
+/// Which search engine the `WEB_SEARCH` tool uses, with the credentials it needs.
+/// Its `Debug` output leaves the credentials out, so it is safe to log.
+#[derive(Clone)]
+pub enum SearchConfig {
+    Google { api_key: String, cx: String },
+    Searxng { url: String },
+    Brave { api_key: String },
+    DuckDuckGo,
+}
+
+impl SearchConfig {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SearchConfig::Google { .. } => "google",
+            SearchConfig::Searxng { .. } => "searxng",
+            SearchConfig::Brave { .. } => "brave",
+            SearchConfig::DuckDuckGo => "duckduckgo",
+        }
+    }
+}
+
+impl fmt::Debug for SearchConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchConfig::Google { .. } => f
+                .debug_struct("Google")
+                .field("api_key", &REDACTED)
+                .field("cx", &REDACTED)
+                .finish(),
+            SearchConfig::Searxng { url } => f.debug_struct("Searxng").field("url", url).finish(),
+            SearchConfig::Brave { .. } => {
+                f.debug_struct("Brave").field("api_key", &REDACTED).finish()
+            }
+            SearchConfig::DuckDuckGo => f.write_str("DuckDuckGo"),
+        }
+    }
+}
+
+/// Where web search keeps what it has already fetched, and for how long.
+/// A TTL of zero turns that part of the cache off.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    pub search_ttl: Duration,
+
+    /// The longest a page is kept. Pages whose `Cache-Control` asks for less are kept for less.
+    pub page_ttl: Duration,
+    pub embedding_ttl: Duration,
+}
+
+/// How web pages are split up before being embedded, measured in embedding model tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkingConfig {
+    pub max_tokens: usize,
+    pub overlap_tokens: usize,
+}
+
+/// How web passages are ranked, by meaning (embeddings) and exact wording (BM25) together.
+#[derive(Debug, Clone, Copy)]
+pub struct RankingConfig {
+    pub fusion: FusionMethod,
+
+    /// The share of the ranking, from 0 to 1, decided by exact wording.
+    pub lexical_weight: f32,
+}
+
+/// The optional second pass over the best-ranked web passages.
+#[derive(Debug, Clone, Copy)]
+pub struct RerankConfig {
+    pub method: RerankMethod,
+
+    /// How many of the best first-stage passages are re-ranked.
+    pub candidates: usize,
+}
+
+/// How the passages handed to the model are picked from the ranked ones.
+#[derive(Debug, Clone, Copy)]
+pub struct SelectionConfig {
+    /// From 0 to 1, how much a passage being like those already picked counts against it.
+    pub diversity: f32,
+
+    /// The embedding similarity, from 0 to 1, at which two passages are near-duplicates.
+    pub duplicate_similarity: f32,
+}
+
+/// How web search fetches pages, and which sites it may fetch them from.
+#[derive(Debug, Clone)]
+pub struct FetchConfig {
+    pub user_agent: String,
+
+    /// Pages larger than this are not read at all.
+    pub max_page_bytes: usize,
+
+    /// If not empty, pages are only fetched from these domains and their subdomains.
+    pub allowed_domains: Vec<String>,
+
+    /// Pages are never fetched from these domains or their subdomains.
+    pub denied_domains: Vec<String>,
+
+    /// The most requests made to one host at once.
+    pub per_domain_concurrency: usize,
+
+    /// The least time between starting requests to one host. Hosts can ask for more in their robots.txt.
+    pub per_domain_delay: Duration,
+}
+
+/// Its `Debug` output leaves the token out, so it is safe to log.
+#[derive(Clone)]
+pub struct HomeAssistantConfig {
+    pub url: String,
+    pub token: String,
+}
+
+impl fmt::Debug for HomeAssistantConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HomeAssistantConfig")
+            .field("url", &self.url)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+/// The config file's contents. Every key is optional, and has the same meaning as the
+/// environment variable of the same name in upper case (e.g. `google_api_key` and `GOOGLE_API_KEY`).
+/// It holds credentials, so it has no `Debug` output.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    rainchain_prompts_dir: Option<PathBuf>,
+    rainchain_memory: Option<String>,
+    rainchain_memory_path: Option<PathBuf>,
+    rainchain_memory_extract_facts: Option<bool>,
+    rainchain_intent_confidence_threshold: Option<f32>,
+    rainchain_intent_fallback: Option<String>,
+    rainchain_search_provider: Option<String>,
+    rainchain_search_mode: Option<String>,
+    rainchain_search_budget_ms: Option<u64>,
+    rainchain_rewrite_timeout_ms: Option<u64>,
+    rainchain_max_sub_queries: Option<usize>,
+    google_api_key: Option<String>,
+    google_cx: Option<String>,
+    searxng_url: Option<String>,
+    brave_search_api_key: Option<String>,
+    home_assistant_url: Option<String>,
+    home_assistant_token: Option<String>,
+    rainchain_cache_dir: Option<PathBuf>,
+    rainchain_search_cache_ttl_secs: Option<u64>,
+    rainchain_page_cache_ttl_secs: Option<u64>,
+    rainchain_embedding_cache_ttl_secs: Option<u64>,
+    rainchain_chunk_max_tokens: Option<usize>,
+    rainchain_chunk_overlap_tokens: Option<usize>,
+    rainchain_rank_fusion: Option<String>,
+    rainchain_lexical_weight: Option<f32>,
+    rainchain_reranker: Option<String>,
+    rainchain_rerank_candidates: Option<usize>,
+    rainchain_diversity: Option<f32>,
+    rainchain_duplicate_similarity: Option<f32>,
+    rainchain_user_agent: Option<String>,
+    rainchain_max_page_bytes: Option<usize>,
+    rainchain_allowed_domains: Option<String>,
+    rainchain_denied_domains: Option<String>,
+    rainchain_per_domain_concurrency: Option<usize>,
+    rainchain_per_domain_delay_ms: Option<u64>,
+}
+
+impl Config {
+    /// Resolves and validates the configuration, failing with a message naming whatever is missing or invalid.
+    pub fn load() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let file = read_config_file()?;
+
+        let prompts_dir = env::var("RAINCHAIN_PROMPTS_DIR")
+            .map(PathBuf::from)
+            .ok()
+            .or(file.rainchain_prompts_dir.clone())
+            .unwrap_or_else(default_prompts_dir);
+
+        if !prompts_dir.is_dir() {
+            return Err(format!(
+                "Prompts directory '{}' does not exist. Set RAINCHAIN_PROMPTS_DIR to the directory holding the prompt templates, or put them in a `prompts` directory beside the executable.",
+                prompts_dir.display()
+            )
+            .into());
+        }
+
+        let memory = memory_config(&file)?;
+        let intent = intent_config(&file)?;
+        let search = search_config(&file)?;
+        let search_mode = search_mode(&file)?;
+        let search_budget = search_budget(&file)?;
+        let rewrite_timeout = rewrite_timeout(&file, search_budget)?;
+        let max_sub_queries = max_sub_queries(&file)?;
+        let home_assistant = home_assistant_config(&file);
+        let cache = cache_config(&file)?;
+        let chunking = chunking_config(&file)?;
+        let ranking = ranking_config(&file)?;
+        let rerank = rerank_config(&file)?;
+        let selection = selection_config(&file)?;
+        let fetch = fetch_config(&file)?;
+
+        Ok(Self {
+            prompts_dir,
+            memory,
+            intent,
+            search,
+            search_mode,
+            search_budget,
+            rewrite_timeout,
+            max_sub_queries,
+            home_assistant,
+            cache,
+            chunking,
+            ranking,
+            rerank,
+            selection,
+            fetch,
+        })
+    }
+
+    pub fn prompts_dir(&self) -> &Path {
+        &self.prompts_dir
+    }
+
+    pub fn memory(&self) -> &MemoryConfig {
+        &self.memory
+    }
+
+    pub fn intent(&self) -> &IntentConfig {
+        &self.intent
+    }
+
+    /// `None` if no search engine is configured, in which case web search is disabled.
+    pub fn search(&self) -> Option<&SearchConfig> {
+        self.search.as_ref()
+    }
+
+    pub fn search_mode(&self) -> SearchMode {
+        self.search_mode
+    }
+
+    /// How long one web search may take, from the query to the passages handed to the model.
+    pub fn search_budget(&self) -> Duration {
+        self.search_budget
+    }
+
+    /// How long one web search may spend working out what to search for, before searching for the input as it is.
+    pub fn rewrite_timeout(&self) -> Duration {
+        self.rewrite_timeout
+    }
+
+    /// The most separate searches a multi-part question is split into.
+    pub fn max_sub_queries(&self) -> usize {
+        self.max_sub_queries
+    }
+
+    /// `None` if no Home Assistant instance is configured, in which case home automation is disabled.
+    pub fn home_assistant(&self) -> Option<&HomeAssistantConfig> {
+        self.home_assistant.as_ref()
+    }
+
+    pub fn cache(&self) -> &CacheConfig {
+        &self.cache
+    }
+
+    pub fn chunking(&self) -> ChunkingConfig {
+        self.chunking
+    }
+
+    pub fn ranking(&self) -> RankingConfig {
+        self.ranking
+    }
+
+    /// `None` if re-ranking is turned off, which it is unless asked for.
+    pub fn rerank(&self) -> Option<RerankConfig> {
+        self.rerank
+    }
+
+    pub fn selection(&self) -> SelectionConfig {
+        self.selection
+    }
+
+    pub fn fetch(&self) -> &FetchConfig {
+        &self.fetch
+    }
+}
+
+/// Makes `config` available through [`get`]. Must be called once, before anything reads the configuration.
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .expect("The configuration is only initialized once");
+}
+
+pub fn get() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("The configuration is initialized at startup")
+}
+
+fn read_config_file() -> Result<ConfigFile, Box<dyn Error + Send + Sync>> {
+    let (path, required) = match env::var("RAINCHAIN_CONFIG") {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+    };
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) if !required => {
+            debug!("No config file at '{}'", path.display());
+            return Ok(ConfigFile::default());
+        }
+        Err(e) => {
+            return Err(format!("Could not read config file '{}': {e}", path.display()).into())
+        }
+    };
+
+    info!("Reading config file: {}", path.display());
+
+    serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid config file '{}': {e}", path.display()).into())
+}
+
+/// The value of environment variable `key`, or else `from_file`. Empty values count as missing.
+fn setting(key: &str, from_file: Option<&String>) -> Option<String> {
+    env::var(key)
+        .ok()
+        .or_else(|| from_file.cloned())
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn required_setting(
+    key: &str,
+    from_file: Option<&String>,
+    provider: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    setting(key, from_file)
+        .ok_or_else(|| format!("The {provider} search provider needs {key} to be set.").into())
+}
+
+/// The `prompts` directory beside the executable, where an installed binary keeps them,
+/// or else `src/prompts` under the working directory, for running from the source tree.
+fn default_prompts_dir() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("prompts")))
+        .filter(|dir| dir.is_dir())
+        .unwrap_or_else(|| PathBuf::from("src/prompts"))
+}
+
+/// Memories are kept on the model server, unless `RAINCHAIN_MEMORY=local` asks for them to be kept in-process.
+fn memory_config(file: &ConfigFile) -> Result<MemoryConfig, Box<dyn Error + Send + Sync>> {
+    let local_path = match setting("RAINCHAIN_MEMORY", file.rainchain_memory.as_ref()).as_deref() {
+        None | Some("remote") => None,
+        Some("local") => Some(
+            env::var("RAINCHAIN_MEMORY_PATH")
+                .map(PathBuf::from)
+                .ok()
+                .or(file.rainchain_memory_path.clone())
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_MEMORY_PATH)),
+        ),
+        Some(other) => {
+            return Err(format!(
+                "Unknown RAINCHAIN_MEMORY '{other}'. Expected one of: remote, local."
+            )
+            .into())
+        }
+    };
+
+    let extract_facts = bool_setting(
+        "RAINCHAIN_MEMORY_EXTRACT_FACTS",
+        file.rainchain_memory_extract_facts,
+    )?;
+
+    if extract_facts && local_path.is_none() {
+        warn!("RAINCHAIN_MEMORY_EXTRACT_FACTS only applies to RAINCHAIN_MEMORY=local, as only the local store is consolidated.");
+    }
+
+    Ok(MemoryConfig {
+        local_path,
+        extract_facts,
+    })
+}
+
+/// Unsure intents are taken to be information retrieval, unless the agent is asked to check with the user instead.
+fn intent_config(file: &ConfigFile) -> Result<IntentConfig, Box<dyn Error + Send + Sync>> {
+    let confidence_threshold = number_setting(
+        "RAINCHAIN_INTENT_CONFIDENCE_THRESHOLD",
+        file.rainchain_intent_confidence_threshold,
+        DEFAULT_INTENT_CONFIDENCE_THRESHOLD,
+    )?;
+
+    if !(0.0..=1.0).contains(&confidence_threshold) {
+        return Err(format!(
+            "RAINCHAIN_INTENT_CONFIDENCE_THRESHOLD must be between 0 and 1, not {confidence_threshold}."
+        )
+        .into());
+    }
+
+    let fallback = match setting(
+        "RAINCHAIN_INTENT_FALLBACK",
+        file.rainchain_intent_fallback.as_ref(),
+    )
+    .as_deref()
+    {
+        None | Some(INTENT_INFORMATION_RETRIEVAL) => {
+            LowConfidenceFallback::DefaultIntent(INTENT_INFORMATION_RETRIEVAL.into())
+        }
+        Some(INTENT_CONVERSATION) => LowConfidenceFallback::DefaultIntent(INTENT_CONVERSATION.into()),
+        Some("clarify") => LowConfidenceFallback::Clarify,
+        Some(other) => {
+            return Err(format!(
+                "Unknown RAINCHAIN_INTENT_FALLBACK '{other}'. Expected one of: {INTENT_INFORMATION_RETRIEVAL}, {INTENT_CONVERSATION}, clarify."
+            )
+            .into())
+        }
+    };
+
+    Ok(IntentConfig {
+        confidence_threshold,
+        fallback,
+    })
+}
+
+/// A provider that was asked for by name must have all its credentials.
+/// Without one, Google is used if its credentials are present, and web search is disabled otherwise.
+fn search_config(file: &ConfigFile) -> Result<Option<SearchConfig>, Box<dyn Error + Send + Sync>> {
+    let provider = setting(
+        "RAINCHAIN_SEARCH_PROVIDER",
+        file.rainchain_search_provider.as_ref(),
+    );
+
+    let google_api_key = file.google_api_key.as_ref();
+    let google_cx = file.google_cx.as_ref();
+
+    let config = match provider.as_deref() {
+        None => {
+            let (Some(api_key), Some(cx)) = (
+                setting("GOOGLE_API_KEY", google_api_key),
+                setting("GOOGLE_CX", google_cx),
+            ) else {
+                warn!("No search provider configured; web search is disabled. Set RAINCHAIN_SEARCH_PROVIDER, or GOOGLE_API_KEY and GOOGLE_CX, to enable it.");
+                return Ok(None);
+            };
+
+            SearchConfig::Google { api_key, cx }
+        }
+        Some("google") => SearchConfig::Google {
+            api_key: required_setting("GOOGLE_API_KEY", google_api_key, "google")?,
+            cx: required_setting("GOOGLE_CX", google_cx, "google")?,
+        },
+        Some("searxng") => SearchConfig::Searxng {
+            url: required_setting("SEARXNG_URL", file.searxng_url.as_ref(), "searxng")?,
+        },
+        Some("brave") => SearchConfig::Brave {
+            api_key: required_setting(
+                "BRAVE_SEARCH_API_KEY",
+                file.brave_search_api_key.as_ref(),
+                "brave",
+            )?,
+        },
+        Some("duckduckgo") => SearchConfig::DuckDuckGo,
+        Some(other) => {
+            return Err(format!(
+                "Unknown search provider '{other}'. Expected one of: google, searxng, brave, duckduckgo."
+            )
+            .into())
+        }
+    };
+
+    Ok(Some(config))
+}
+
+/// Full searches unless snippets alone are asked for, trading answer quality for speed.
+fn search_mode(file: &ConfigFile) -> Result<SearchMode, Box<dyn Error + Send + Sync>> {
+    match setting("RAINCHAIN_SEARCH_MODE", file.rainchain_search_mode.as_ref()).as_deref() {
+        None | Some("full") => Ok(SearchMode::Full),
+        Some("snippets") => {
+            info!("Web search answers from search snippets only");
+            Ok(SearchMode::SnippetsOnly)
+        }
+        Some(other) => Err(format!(
+            "Unknown RAINCHAIN_SEARCH_MODE '{other}'. Expected one of: full, snippets."
+        )
+        .into()),
+    }
+}
+
+fn search_budget(file: &ConfigFile) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+    let budget_ms = number_setting(
+        "RAINCHAIN_SEARCH_BUDGET_MS",
+        file.rainchain_search_budget_ms,
+        DEFAULT_TIME_BUDGET_MS,
+    )?;
+
+    if budget_ms == 0 {
+        return Err("RAINCHAIN_SEARCH_BUDGET_MS must be at least 1.".into());
+    }
+
+    Ok(Duration::from_millis(budget_ms))
+}
+
+fn rewrite_timeout(
+    file: &ConfigFile,
+    search_budget: Duration,
+) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+    let timeout = Duration::from_millis(number_setting(
+        "RAINCHAIN_REWRITE_TIMEOUT_MS",
+        file.rainchain_rewrite_timeout_ms,
+        DEFAULT_REWRITE_TIMEOUT_MS,
+    )?);
+
+    // Searching has to start before the whole budget is gone:
+    if timeout.is_zero() || timeout >= search_budget {
+        return Err(format!(
+            "RAINCHAIN_REWRITE_TIMEOUT_MS must be at least 1, and less than the search budget of {}ms.",
+            search_budget.as_millis()
+        )
+        .into());
+    }
+
+    Ok(timeout)
+}
+
+fn max_sub_queries(file: &ConfigFile) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let max_sub_queries = number_setting(
+        "RAINCHAIN_MAX_SUB_QUERIES",
+        file.rainchain_max_sub_queries,
+        DEFAULT_MAX_SUB_QUERIES,
+    )?;
+
+    if max_sub_queries == 0 {
+        return Err("RAINCHAIN_MAX_SUB_QUERIES must be at least 1.".into());
+    }
+
+    Ok(max_sub_queries)
+}
+
+fn home_assistant_config(file: &ConfigFile) -> Option<HomeAssistantConfig> {
+    let (Some(url), Some(token)) = (
+        setting("HOME_ASSISTANT_URL", file.home_assistant_url.as_ref()),
+        setting("HOME_ASSISTANT_TOKEN", file.home_assistant_token.as_ref()),
+    ) else {
+        info!("HOME_ASSISTANT_URL or HOME_ASSISTANT_TOKEN not set; home automation is disabled.");
+        return None;
+    };
+
+    Some(HomeAssistantConfig { url, token })
+}
+
+fn cache_config(file: &ConfigFile) -> Result<CacheConfig, Box<dyn Error + Send + Sync>> {
+    let dir = env::var("RAINCHAIN_CACHE_DIR")
+        .map(PathBuf::from)
+        .ok()
+        .or(file.rainchain_cache_dir.clone())
+        .unwrap_or_else(|| env::temp_dir().join("rainchain-cache"));
+
+    debug!("Caching web search data in {}", dir.display());
+
+    Ok(CacheConfig {
+        dir,
+        search_ttl: ttl_setting(
+            "RAINCHAIN_SEARCH_CACHE_TTL_SECS",
+            file.rainchain_search_cache_ttl_secs,
+            DEFAULT_SEARCH_CACHE_TTL,
+        )?,
+        page_ttl: ttl_setting(
+            "RAINCHAIN_PAGE_CACHE_TTL_SECS",
+            file.rainchain_page_cache_ttl_secs,
+            DEFAULT_PAGE_CACHE_TTL,
+        )?,
+        embedding_ttl: ttl_setting(
+            "RAINCHAIN_EMBEDDING_CACHE_TTL_SECS",
+            file.rainchain_embedding_cache_ttl_secs,
+            DEFAULT_EMBEDDING_CACHE_TTL,
+        )?,
+    })
+}
+
+/// A duration given in whole seconds by environment variable `key`, or else `from_file`, or else `default`.
+fn ttl_setting(
+    key: &str,
+    from_file: Option<u64>,
+    default: Duration,
+) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+    Ok(Duration::from_secs(number_setting(
+        key,
+        from_file,
+        default.as_secs(),
+    )?))
+}
+
+/// A flag given as `1` or `0` by environment variable `key`, or else `from_file`. Off if neither is set.
+fn bool_setting(key: &str, from_file: Option<bool>) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    match env::var(key).as_deref().map(str::trim) {
+        Ok("1" | "true") => Ok(true),
+        Ok("0" | "false" | "") => Ok(false),
+        Ok(other) => Err(format!("{key} must be 1 or 0, not '{other}'.").into()),
+        Err(_) => Ok(from_file.unwrap_or(false)),
+    }
+}
+
+/// A number given by environment variable `key`, or else `from_file`, or else `default`.
+fn number_setting<T>(
+    key: &str,
+    from_file: Option<T>,
+    default: T,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env::var(key) {
+        Ok(value) => value
+            .trim()
+            .parse::<T>()
+            .map_err(|e| format!("{key} must be a number, not '{value}': {e}").into()),
+        Err(_) => Ok(from_file.unwrap_or(default)),
+    }
+}
+
+fn chunking_config(file: &ConfigFile) -> Result<ChunkingConfig, Box<dyn Error + Send + Sync>> {
+    let max_tokens = number_setting(
+        "RAINCHAIN_CHUNK_MAX_TOKENS",
+        file.rainchain_chunk_max_tokens,
+        DEFAULT_CHUNK_MAX_TOKENS,
+    )?;
+    let overlap_tokens = number_setting(
+        "RAINCHAIN_CHUNK_OVERLAP_TOKENS",
+        file.rainchain_chunk_overlap_tokens,
+        DEFAULT_CHUNK_OVERLAP_TOKENS,
+    )?;
+
+    if max_tokens == 0 || max_tokens > EMBEDDING_MODEL_MAX_TOKENS {
+        return Err(format!(
+            "RAINCHAIN_CHUNK_MAX_TOKENS must be between 1 and {EMBEDDING_MODEL_MAX_TOKENS}, the most the embedding model reads, not {max_tokens}."
+        )
+        .into());
+    }
+
+    if overlap_tokens >= max_tokens {
+        return Err(format!(
+            "RAINCHAIN_CHUNK_OVERLAP_TOKENS ({overlap_tokens}) must be less than RAINCHAIN_CHUNK_MAX_TOKENS ({max_tokens})."
+        )
+        .into());
+    }
+
+    Ok(ChunkingConfig {
+        max_tokens,
+        overlap_tokens,
+    })
+}
+
+fn ranking_config(file: &ConfigFile) -> Result<RankingConfig, Box<dyn Error + Send + Sync>> {
+    let fusion =
+        match setting("RAINCHAIN_RANK_FUSION", file.rainchain_rank_fusion.as_ref()).as_deref() {
+            None | Some("rrf") => FusionMethod::ReciprocalRank,
+            Some("weighted") => FusionMethod::Weighted,
+            Some(other) => {
+                return Err(format!(
+                    "Unknown RAINCHAIN_RANK_FUSION '{other}'. Expected one of: rrf, weighted."
+                )
+                .into())
+            }
+        };
+
+    let lexical_weight = number_setting(
+        "RAINCHAIN_LEXICAL_WEIGHT",
+        file.rainchain_lexical_weight,
+        DEFAULT_LEXICAL_WEIGHT,
+    )?;
+
+    if !(0.0..=1.0).contains(&lexical_weight) {
+        return Err(format!(
+            "RAINCHAIN_LEXICAL_WEIGHT must be between 0 and 1, not {lexical_weight}."
+        )
+        .into());
+    }
+
+    Ok(RankingConfig {
+        fusion,
+        lexical_weight,
+    })
+}
+
+fn rerank_config(file: &ConfigFile) -> Result<Option<RerankConfig>, Box<dyn Error + Send + Sync>> {
+    let method = match setting("RAINCHAIN_RERANKER", file.rainchain_reranker.as_ref()).as_deref() {
+        None | Some("none") => return Ok(None),
+        Some("grades") => RerankMethod::Grades,
+        Some("cross_encoder") => RerankMethod::CrossEncoder,
+        Some(other) => {
+            return Err(format!(
+            "Unknown RAINCHAIN_RERANKER '{other}'. Expected one of: none, grades, cross_encoder."
+        )
+            .into())
+        }
+    };
+
+    let candidates = number_setting(
+        "RAINCHAIN_RERANK_CANDIDATES",
+        file.rainchain_rerank_candidates,
+        DEFAULT_RERANK_CANDIDATES,
+    )?;
+
+    if candidates == 0 {
+        return Err("RAINCHAIN_RERANK_CANDIDATES must be at least 1.".into());
+    }
+
+    Ok(Some(RerankConfig { method, candidates }))
+}
+
+fn selection_config(file: &ConfigFile) -> Result<SelectionConfig, Box<dyn Error + Send + Sync>> {
+    let diversity = number_setting(
+        "RAINCHAIN_DIVERSITY",
+        file.rainchain_diversity,
+        DEFAULT_DIVERSITY,
+    )?;
+
+    if !(0.0..=1.0).contains(&diversity) {
+        return Err(
+            format!("RAINCHAIN_DIVERSITY must be between 0 and 1, not {diversity}.").into(),
+        );
+    }
+
+    let duplicate_similarity = number_setting(
+        "RAINCHAIN_DUPLICATE_SIMILARITY",
+        file.rainchain_duplicate_similarity,
+        DEFAULT_DUPLICATE_SIMILARITY,
+    )?;
+
+    if !(0.0..=1.0).contains(&duplicate_similarity) {
+        return Err(format!(
+            "RAINCHAIN_DUPLICATE_SIMILARITY must be between 0 and 1, not {duplicate_similarity}."
+        )
+        .into());
+    }
+
+    Ok(SelectionConfig {
+        diversity,
+        duplicate_similarity,
+    })
+}
+
+fn fetch_config(file: &ConfigFile) -> Result<FetchConfig, Box<dyn Error + Send + Sync>> {
+    let user_agent = setting("RAINCHAIN_USER_AGENT", file.rainchain_user_agent.as_ref())
+        .unwrap_or_else(|| DEFAULT_USER_AGENT.to_owned());
+
+    let max_page_bytes = number_setting(
+        "RAINCHAIN_MAX_PAGE_BYTES",
+        file.rainchain_max_page_bytes,
+        DEFAULT_MAX_PAGE_BYTES,
+    )?;
+
+    if max_page_bytes == 0 {
+        return Err("RAINCHAIN_MAX_PAGE_BYTES must be at least 1.".into());
+    }
+
+    let allowed_domains = list_setting(
+        "RAINCHAIN_ALLOWED_DOMAINS",
+        file.rainchain_allowed_domains.as_ref(),
+    );
+    let denied_domains = list_setting(
+        "RAINCHAIN_DENIED_DOMAINS",
+        file.rainchain_denied_domains.as_ref(),
+    );
+
+    if !allowed_domains.is_empty() {
+        info!(
+            "Only fetching web pages from: {}",
+            allowed_domains.join(", ")
+        );
+    }
+
+    let per_domain_concurrency = number_setting(
+        "RAINCHAIN_PER_DOMAIN_CONCURRENCY",
+        file.rainchain_per_domain_concurrency,
+        DEFAULT_PER_DOMAIN_CONCURRENCY,
+    )?;
+
+    if per_domain_concurrency == 0 {
+        return Err("RAINCHAIN_PER_DOMAIN_CONCURRENCY must be at least 1.".into());
+    }
+
+    let per_domain_delay = Duration::from_millis(number_setting(
+        "RAINCHAIN_PER_DOMAIN_DELAY_MS",
+        file.rainchain_per_domain_delay_ms,
+        DEFAULT_PER_DOMAIN_DELAY_MS,
+    )?);
+
+    Ok(FetchConfig {
+        user_agent,
+        max_page_bytes,
+        allowed_domains,
+        denied_domains,
+        per_domain_concurrency,
+        per_domain_delay,
+    })
+}
+
+/// A comma-separated list given by environment variable `key`, or else `from_file`. Empty if neither is set.
+fn list_setting(key: &str, from_file: Option<&String>) -> Vec<String> {
+    setting(key, from_file)
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -1,3 +1,5 @@
+use std::fmt::Write;
+
 #[derive(Default, Clone, Debug)]
 pub struct Conversation {
     messages: Vec<ChatMessage>,
@@ -32,22 +34,6 @@ impl ChatMessage {
     pub fn is_user(&self) -> bool {
         matches!(self, Self::User(..))
     }
-
-    /// Returns `true` if the chat message is [`Assistant`].
-    ///
-    /// [`Assistant`]: ChatMessage::Assistant
-    #[must_use]
-    pub fn is_assistant(&self) -> bool {
-        matches!(self, Self::Assistant(..))
-    }
-
-    /// Returns `true` if the chat message is [`System`].
-    ///
-    /// [`System`]: ChatMessage::System
-    #[must_use]
-    pub fn is_system(&self) -> bool {
-        matches!(self, Self::System(..))
-    }
 }
 
 impl Conversation {
@@ -69,27 +55,37 @@ impl Conversation {
         let mut result = String::new();
 
         for message in self.messages() {
-            let (role_start, role_end) = match message {
-                ChatMessage::User(_) => ("{{~#user~}}", "{{~/user}}"),
-                ChatMessage::Assistant(_) => ("{{~#assistant}}", "{{~/assistant}}"),
-                ChatMessage::System(_) => ("<<SYS>>", "<</SYS>>"),
-            };
-
-            let text = message.text();
-
-            result.push_str(&format!("{role_start}{text}{role_end}"));
+            result.push_str(&Self::format_message(message));
         }
 
         result
     }
 
+    /// Formats a single message the way it appears in [`Conversation::build_history`].
+    pub fn format_message(message: &ChatMessage) -> String {
+        let (role_start, role_end) = match message {
+            ChatMessage::User(_) => ("{{~#user~}}", "{{~/user}}"),
+            ChatMessage::Assistant(_) => ("{{~#assistant}}", "{{~/assistant}}"),
+            ChatMessage::System(_) => ("{{~#system~}}", "{{~/system}}"),
+        };
+
+        let text = message.text();
+
+        format!("{role_start}{text}{role_end}")
+    }
+
+    /// Each turn of the conversation: a user's message, and everything up to the next one.
+    pub fn turns(&self) -> impl Iterator<Item = &[ChatMessage]> {
+        self.messages.chunk_by(|_, next| !next.is_user())
+    }
+
     pub fn messages_to_string<'a>(messages: impl IntoIterator<Item = &'a ChatMessage>) -> String {
         let mut result = String::new();
 
         for message in messages {
             let role = message.role();
             let text = message.text().trim();
-            result.push_str(&format!("{role}: {text}\n"));
+            let _ = writeln!(result, "{role}: {text}");
         }
 
         // pop trailing newline
@@ -98,3 +94,37 @@ impl Conversation {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_into_turns_at_each_user_message() {
+        let mut conversation = Conversation::new();
+        for message in [
+            ChatMessage::User(String::from("turn on the light")),
+            ChatMessage::System(String::from("The user approved light.turn_on.")),
+            ChatMessage::Assistant(String::from(" Done! ")),
+            ChatMessage::User(String::from("thanks")),
+            ChatMessage::Assistant(String::from("You're welcome.")),
+            ChatMessage::User(String::from("bye")),
+        ] {
+            conversation.add_message(message);
+        }
+
+        let turns: Vec<String> = conversation
+            .turns()
+            .map(Conversation::messages_to_string)
+            .collect();
+
+        assert_eq!(
+            turns,
+            [
+                "USER: turn on the light\nSYSTEM: The user approved light.turn_on.\nASSISTANT: Done!",
+                "USER: thanks\nASSISTANT: You're welcome.",
+                "USER: bye",
+            ]
+        );
+    }
+}
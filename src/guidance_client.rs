@@ -3,15 +3,15 @@ use futures::stream::StreamExt;
 
 use futures_util::Stream;
 use log::info;
-use reqwest::Url;
+use reqwest::{Method, Url};
 use reqwest_eventsource::{Event, EventSource, RequestBuilderExt};
 
-use std::{collections::HashMap, future, pin::Pin, time::Duration};
+use std::{collections::HashMap, error::Error, future, time::Duration};
 
 use crate::model_client::{
     EmbeddingsResponse, GuidanceEmbeddingsRequest, GuidanceEmbeddingsRequestBuilder,
     GuidanceRequest, GuidanceResponse, MemoryGetRequest, MemoryGetResponse, MemoryStoreRequest,
-    ModelClient,
+    ModelClient, RerankRequest, RerankResponse,
 };
 
 pub struct GuidanceClient {
@@ -49,49 +49,53 @@ impl GuidanceClient {
                             .expect("response was not in the expected format");
                         info!("got message: {response:?}");
 
-                        final_response.text.push_str(response.text());
-
-                        for (k, v) in response.variables {
-                            if let Some(current) = final_response.variables.get_mut(&k) {
-                                current.push_str(&v);
-                            } else {
-                                final_response.variables.insert(k, v);
-                            }
-                        }
+                        final_response.apply_delta(response);
                     }
                 },
                 _ => break,
             }
         }
 
-        info!("done. final:\n{:#?}", final_response);
+        info!("done. final:\n{final_response:#?}");
 
         final_response
     }
 
-    async fn store_memory(&self, request: &MemoryStoreRequest) {
+    async fn send_memory_request(
+        &self,
+        method: Method,
+        body: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let client = reqwest::Client::new();
 
         let url = Url::parse(&format!("{}/memory", self.uri))
             .expect("Failed to parse guidance memory url");
 
-        let body = serde_json::to_string(request)
-            .expect("Failed to parse guidance memory request to json");
-
-        info!("Sending guidance memory request to {url}...");
+        info!("Sending guidance memory {method} request to {url}...");
         info!("{body}");
 
         client
-            .post(url)
+            .request(method, url)
             .body(body)
-            .timeout(Duration::from_secs(120))
+            .timeout(Duration::from_mins(2))
             .send()
-            .await
-            .expect("Failed to send guidance memory request")
+            .await?
+            .error_for_status()?
             .text()
-            .await
-            .expect("Expected text response");
+            .await?;
         info!("...Got response.");
+
+        Ok(())
+    }
+
+    async fn store_memory(
+        &self,
+        request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let body = serde_json::to_string(request)
+            .expect("Failed to parse guidance memory request to json");
+
+        self.send_memory_request(Method::POST, body).await
     }
 
     async fn get_memory_response(&self, request: &MemoryGetRequest) -> MemoryGetResponse {
@@ -102,6 +106,12 @@ impl GuidanceClient {
 
             map.insert("query".to_owned(), request.query.clone());
 
+            if !request.filter.is_empty() {
+                let filter = serde_json::to_string(&request.filter)
+                    .expect("Failed to parse guidance memory filter to json");
+                map.insert("where".to_owned(), filter);
+            }
+
             map
         };
 
@@ -126,6 +136,39 @@ impl GuidanceClient {
         parsed
     }
 
+    async fn get_rerank(
+        &self,
+        request: &RerankRequest,
+    ) -> Result<RerankResponse, Box<dyn Error + Send + Sync>> {
+        let client = reqwest::Client::new();
+
+        let url = Url::parse(&format!("{}/rerank", self.uri))
+            .expect("Failed to parse guidance rerank url");
+
+        info!("Sending guidance rerank request to {url}...");
+        let response = client
+            .post(url)
+            .json(request)
+            .timeout(Duration::from_mins(1))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<RerankResponse>()
+            .await?;
+        info!("...Got response.");
+
+        if response.scores.len() != request.documents.len() {
+            return Err(format!(
+                "Expected {} rerank scores, got {}",
+                request.documents.len(),
+                response.scores.len()
+            )
+            .into());
+        }
+
+        Ok(response)
+    }
+
     pub async fn get_embeddings(&self, request: &GuidanceEmbeddingsRequest) -> EmbeddingsResponse {
         let client = reqwest::Client::new();
 
@@ -139,7 +182,7 @@ impl GuidanceClient {
         let json = client
             .post(url)
             .body(body)
-            .timeout(Duration::from_secs(120))
+            .timeout(Duration::from_mins(2))
             .send()
             .await
             .expect("Failed to send guidance embeddings request")
@@ -160,7 +203,7 @@ impl ModelClient for GuidanceClient {
         &self,
         request: &crate::model_client::EmbeddingsRequest,
     ) -> crate::model_client::EmbeddingsResponse {
-        let mut mapped_request = GuidanceEmbeddingsRequestBuilder::default();
+        let mut mapped_request = GuidanceEmbeddingsRequestBuilder::new();
 
         for r in &request.input {
             mapped_request = mapped_request.add_input(r);
@@ -173,12 +216,22 @@ impl ModelClient for GuidanceClient {
         self.get_response(request).await
     }
 
+    async fn request_rerank(
+        &self,
+        request: &RerankRequest,
+    ) -> Result<RerankResponse, Box<dyn Error + Send + Sync>> {
+        self.get_rerank(request).await
+    }
+
     async fn request_memory(&self, request: &MemoryGetRequest) -> MemoryGetResponse {
         self.get_memory_response(request).await
     }
 
-    async fn store_memory(&self, request: &MemoryStoreRequest) {
-        self.store_memory(request).await;
+    async fn store_memory(
+        &self,
+        request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store_memory(request).await
     }
 
     fn request_guidance_stream(
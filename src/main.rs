@@ -1,27 +1,50 @@
 #![deny(clippy::pedantic)]
 #![allow(clippy::module_name_repetitions, clippy::too_many_lines)]
 
-use std::{env, fs};
+use std::{env, fs, path::Path, sync::Arc, time::Duration};
 
 use env_logger::Env;
 use guidance_client::GuidanceClient;
-use log::debug;
+use log::{debug, info};
 use model_client::ModelClient;
 
 use crate::{
-    agents::ThoughtActionAgent,
+    agents::{Agent, ThoughtActionAgent},
+    cache::DiskCache,
+    config::{Config, SearchConfig},
+    memory::{
+        generate_id, LocalMemoryStore, MemoryConsolidator, MemoryScope, MemoryStore, MemoryWriter,
+        RemoteMemoryStore,
+    },
     server::{Server, WebsocketServer},
     session::AgentSessionHandler,
+    tools::{
+        home_automation::HomeAutomation,
+        web_search::{
+            BraveSearch, Chunker, DuckDuckGoSearch, Fetcher, GoogleSearch, HybridRanker,
+            PassageSelector, Reranker, SearchProvider, SearxngSearch, WebSearch,
+        },
+        Tool,
+    },
 };
 
 mod agents;
+mod cache;
+mod config;
 mod conversation;
 mod guidance_client;
+mod memory;
 mod model_client;
 mod server;
 mod session;
 mod tools;
 
+const MEMORY_CONSOLIDATION_INTERVAL: Duration = Duration::from_hours(1);
+const CACHE_SWEEP_INTERVAL: Duration = Duration::from_hours(1);
+
+/// Whose memories a question asked on the command line reads and adds to.
+const ONE_SHOT_USER_ID: &str = "command_line";
+
 #[tokio::main]
 async fn main() {
     // Logging startup
@@ -31,19 +54,99 @@ async fn main() {
         debug!("Starting up.");
     }
 
-    let url = env::args()
-        .nth(1)
-        .expect("Expected a single argument for the target guidance server url");
+    // Everything else reads configuration, so it is resolved, and any problem reported, first:
+    {
+        let config = Config::load().unwrap_or_else(|e| panic!("Invalid configuration: {e}"));
+        config::init(config);
+    }
+
+    let url = env::args().nth(1).expect(
+        "Expected the target guidance server url, optionally followed by a question to answer",
+    );
 
     // Listens for connections from browsers
     let server = make_server();
 
+    // Memory is shared by every session:
+    let memory_config = config::get().memory();
+    let memory_store = make_memory_store(&url, memory_config.local_path.as_deref());
+
+    // Memory writes happen in the background, so turns never wait on them:
+    let memory_writer = MemoryWriter::spawn(memory_store.clone());
+
+    // Tools beyond the default are only offered when they are configured:
+    let tools = make_tools();
+
+    // Given a question after the url, answers just that one and exits, rather than serving:
+    if let Some(question) = env::args().nth(2) {
+        let agent = ThoughtActionAgent::new(
+            Box::new(make_client(url)),
+            memory_store,
+            memory_writer.clone(),
+            MemoryScope::new(ONE_SHOT_USER_ID, generate_id()),
+        );
+        let mut agent = tools.into_iter().fold(agent, ThoughtActionAgent::with_tool);
+
+        let result = agent.get_response(&question).await;
+        info!(
+            "Took action {}({}), which output:\n{}",
+            result.action, result.action_input, result.tool_output
+        );
+
+        println!("{}", result.response);
+        for source in &result.sources {
+            println!("- {} <{}>", source.title, source.url);
+        }
+
+        memory_writer.flush().await;
+        return;
+    }
+
+    // Tidies up memory in the background, for as long as the server runs.
+    // It needs to list every memory, which only the local store can do:
+    if memory_config.local_path.is_some() {
+        let consolidator = MemoryConsolidator::new(
+            memory_store.clone(),
+            memory_writer.clone(),
+            Box::new(make_client(url.clone())),
+            memory_config.extract_facts,
+        );
+        tokio::spawn(consolidator.run(MEMORY_CONSOLIDATION_INTERVAL));
+    }
+
+    // Clears out web search cache entries that expired without ever being read again:
+    if config::get().search().is_some() {
+        let cache = DiskCache::new(&config::get().cache().dir);
+        tokio::spawn(cache.sweep_every(CACHE_SWEEP_INTERVAL));
+    }
+
     // let session_handler = Session::new(move || Box::new(make_client(url)));
-    let session_handler =
-        AgentSessionHandler::new(|| Box::new(ThoughtActionAgent::new(Box::new(make_client(url)))));
+    let agent_memory_store = memory_store.clone();
+    let agent_memory_writer = memory_writer.clone();
+    let session_handler = AgentSessionHandler::new(
+        |memory_scope| {
+            let agent = ThoughtActionAgent::new(
+                Box::new(make_client(url)),
+                agent_memory_store,
+                agent_memory_writer,
+                memory_scope,
+            );
+
+            Box::new(tools.into_iter().fold(agent, ThoughtActionAgent::with_tool))
+        },
+        memory_store,
+        memory_writer.clone(),
+    );
 
     debug!("Starting server.");
-    server.run(session_handler).await;
+    tokio::select! {
+        () = server.run(session_handler) => {}
+        _ = tokio::signal::ctrl_c() => info!("Shutting down."),
+    }
+
+    debug!("Flushing memory writes...");
+    memory_writer.flush().await;
+    debug!("...Done.");
 }
 
 fn make_server() -> impl Server {
@@ -56,8 +159,79 @@ fn make_client(url: impl Into<String>) -> impl ModelClient + Sync + Send {
     GuidanceClient::new(url)
 }
 
+fn make_tools() -> Vec<Arc<dyn Tool + Send + Sync>> {
+    let config = config::get();
+    let mut tools = Vec::<Arc<dyn Tool + Send + Sync>>::new();
+
+    if let Some(search) = config.search() {
+        let chunking = config.chunking();
+        let ranking = config.ranking();
+        let selection = config.selection();
+        let fetch = config.fetch();
+
+        let mut web_search = WebSearch::new(
+            make_search_provider(search),
+            Fetcher::new(&fetch.user_agent, fetch.max_page_bytes)
+                .with_allowed_domains(&fetch.allowed_domains)
+                .with_denied_domains(&fetch.denied_domains)
+                .with_politeness(fetch.per_domain_concurrency, fetch.per_domain_delay),
+            config.cache().clone(),
+            Chunker::new(chunking.max_tokens, chunking.overlap_tokens),
+            HybridRanker::new(ranking.fusion, ranking.lexical_weight),
+            PassageSelector::new(selection.diversity, selection.duplicate_similarity),
+        )
+        .with_mode(config.search_mode())
+        .with_time_budget(config.search_budget())
+        .with_rewrite_timeout(config.rewrite_timeout())
+        .with_max_sub_queries(config.max_sub_queries());
+
+        if let Some(rerank) = config.rerank() {
+            web_search = web_search.with_reranker(Reranker::new(rerank.method, rerank.candidates));
+        }
+
+        tools.push(Arc::new(web_search));
+    }
+
+    if let Some(home_assistant) = config.home_assistant() {
+        info!("Home automation enabled for {}", home_assistant.url);
+        tools.push(Arc::new(HomeAutomation::new(
+            &home_assistant.url,
+            &home_assistant.token,
+        )));
+    }
+
+    tools
+}
+
+fn make_search_provider(config: &SearchConfig) -> Arc<dyn SearchProvider + Send + Sync> {
+    info!("Using search provider: {}", config.name());
+
+    match config {
+        SearchConfig::Google { api_key, cx } => Arc::new(GoogleSearch::new(api_key, cx)),
+        SearchConfig::Searxng { url } => Arc::new(SearxngSearch::new(url)),
+        SearchConfig::Brave { api_key } => Arc::new(BraveSearch::new(api_key)),
+        SearchConfig::DuckDuckGo => Arc::new(DuckDuckGoSearch),
+    }
+}
+
+/// Memory lives on the guidance server, unless a `local_path` is given to keep it in-process and save it to.
+fn make_memory_store(url: &str, local_path: Option<&Path>) -> Arc<dyn MemoryStore + Send + Sync> {
+    let client = Box::new(make_client(url.to_owned()));
+
+    if let Some(path) = local_path {
+        debug!("Using local memory store at: {}", path.display());
+        let store = LocalMemoryStore::open(path, client)
+            .unwrap_or_else(|e| panic!("Could not open the local memory store: {e}"));
+        Arc::new(store)
+    } else {
+        debug!("Using remote memory store");
+        Arc::new(RemoteMemoryStore::new(client))
+    }
+}
+
 pub(crate) fn load_prompt_text(prompt_name: &str) -> String {
-    let path = format!("src/prompts/{prompt_name}");
-    debug!("Reading prompt file: {path}");
-    fs::read_to_string(path).expect("Failed to read prompt file")
+    let path = config::get().prompts_dir().join(prompt_name);
+    debug!("Reading prompt file: {}", path.display());
+    fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read prompt file {}: {e}", path.display()))
 }
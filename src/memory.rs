@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+
+use crate::model_client::{
+    MemoryDeleteRequest, MemoryGetRequest, MemoryGetResponse, MemoryListRequest, MemoryStoreRequest,
+};
+
+mod consolidation;
+#[cfg(test)]
+mod keyword_embedder;
+mod local;
+mod remote;
+mod writer;
+
+pub use consolidation::MemoryConsolidator;
+pub use local::LocalMemoryStore;
+pub use remote::RemoteMemoryStore;
+pub use writer::MemoryWriter;
+
+pub const METADATA_SESSION_ID: &str = "session_id";
+pub const METADATA_USER_ID: &str = "user_id";
+pub const METADATA_TIMESTAMP: &str = "timestamp";
+pub const METADATA_SOURCE: &str = "source";
+pub const METADATA_REINFORCED_AT: &str = "reinforced_at";
+
+/// Every this many seconds without being written or reinforced, a memory counts for half as much.
+const DECAY_HALF_LIFE_SECS: f32 = 30.0 * 24.0 * 60.0 * 60.0;
+
+/// Long-term storage for documents the agent may want to recall later, searchable by meaning.
+#[async_trait]
+pub trait MemoryStore {
+    /// Stores each document in the request. A document with an empty id is given a new one.
+    /// A document with the id of one already stored replaces it, so storing the same request twice stores it once.
+    async fn store(&self, request: &MemoryStoreRequest)
+        -> Result<(), Box<dyn Error + Send + Sync>>;
+    async fn query(&self, request: &MemoryGetRequest) -> MemoryGetResponse;
+
+    /// The most recent memories matching the request's ids and filter, newest first, from the request's offset on.
+    /// Distances are left empty. Fails if the store has no way of listing memories.
+    async fn list(
+        &self,
+        request: &MemoryListRequest,
+    ) -> Result<MemoryGetResponse, Box<dyn Error + Send + Sync>>;
+
+    /// Whether memories can be listed, updated and deleted, rather than only stored and searched.
+    /// If not, `list`, `update` and `delete` always fail.
+    fn can_manage(&self) -> bool {
+        true
+    }
+
+    /// Replaces the document and metadata of each memory with a matching id.
+    /// Memories whose document is unchanged, as when only reinforcing them, are not re-embedded.
+    async fn update(
+        &self,
+        request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+    async fn delete(
+        &self,
+        request: &MemoryDeleteRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// What produced a memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySource {
+    ChatTurn,
+
+    /// Merged from several similar memories by the consolidation job.
+    Consolidated,
+}
+
+impl MemorySource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MemorySource::ChatTurn => "chat_turn",
+            MemorySource::Consolidated => "consolidated",
+        }
+    }
+}
+
+/// Who a session's memories belong to.
+/// Memories are always written with, and queried by, the user id, so users never see each other's memories.
+/// The id is only as private as whatever it was derived from: anyone who can present the same user token
+/// to the server is the same user to it, and can see and forget their memories.
+#[derive(Debug, Clone)]
+pub struct MemoryScope {
+    user_id: String,
+    session_id: String,
+}
+
+impl MemoryScope {
+    pub fn new(user_id: impl Into<String>, session_id: impl Into<String>) -> Self {
+        Self {
+            user_id: user_id.into(),
+            session_id: session_id.into(),
+        }
+    }
+
+    /// Metadata for a new memory from `source`, stamped with the current time.
+    pub fn metadata(&self, source: MemorySource) -> HashMap<String, String> {
+        HashMap::from([
+            (METADATA_USER_ID.to_owned(), self.user_id.clone()),
+            (METADATA_SESSION_ID.to_owned(), self.session_id.clone()),
+            (METADATA_TIMESTAMP.to_owned(), unix_timestamp().to_string()),
+            (METADATA_SOURCE.to_owned(), source.as_str().to_owned()),
+        ])
+    }
+
+    /// A filter matching every memory belonging to this user, from any session.
+    pub fn user_filter(&self) -> HashMap<String, String> {
+        HashMap::from([(METADATA_USER_ID.to_owned(), self.user_id.clone())])
+    }
+}
+
+/// True if `metadata` has every key/value pair in `filter`.
+pub fn matches_filter(
+    metadata: &HashMap<String, String>,
+    filter: &HashMap<String, String>,
+) -> bool {
+    filter
+        .iter()
+        .all(|(key, value)| metadata.get(key) == Some(value))
+}
+
+/// How much of its relevance a memory keeps at time `now`, between 1.0 (fresh) and 0.0.
+/// Decays exponentially from when the memory was written, or last reinforced by being recalled.
+pub fn decay(metadata: &HashMap<String, String>, now: u64) -> f32 {
+    let last_touched = [METADATA_REINFORCED_AT, METADATA_TIMESTAMP]
+        .into_iter()
+        .filter_map(|key| metadata.get(key)?.parse::<u64>().ok())
+        .max();
+
+    // Memories from before timestamps were recorded don't decay:
+    let Some(last_touched) = last_touched else {
+        return 1.0;
+    };
+
+    #[allow(clippy::cast_precision_loss)]
+    let age = now.saturating_sub(last_touched) as f32;
+
+    0.5_f32.powf(age / DECAY_HALF_LIFE_SECS)
+}
+
+/// Marks a memory as just used, resetting its decay.
+pub fn reinforce(metadata: &mut HashMap<String, String>) {
+    metadata.insert(
+        METADATA_REINFORCED_AT.to_owned(),
+        unix_timestamp().to_string(),
+    );
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the unix epoch")
+        .as_secs()
+}
+
+/// A new id, unique within this process and very likely unique across restarts.
+pub fn generate_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the unix epoch")
+        .as_nanos();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{nanos:x}-{count:x}")
+}
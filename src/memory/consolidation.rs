@@ -0,0 +1,383 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info};
+
+use crate::{
+    load_prompt_text,
+    model_client::{
+        Embedding, EmbeddingsRequest, GuidanceRequestBuilder, MemoryDeleteRequest,
+        MemoryListRequest, MemoryStoreRequest, ModelClient,
+    },
+    tools::web_search::cosine_similarity,
+};
+
+use super::{
+    decay, unix_timestamp, MemorySource, MemoryStore, MemoryWriter, METADATA_REINFORCED_AT,
+    METADATA_SESSION_ID, METADATA_SOURCE, METADATA_TIMESTAMP, METADATA_USER_ID,
+};
+
+/// Memories at least this similar to each other are considered duplicates.
+const DUPLICATE_SIMILARITY: f32 = 0.92;
+
+/// Memories that have decayed below this are forgotten entirely.
+const MIN_RETAINED_WEIGHT: f32 = 0.01;
+
+/// How many memories are listed at a time.
+const PAGE_SIZE: usize = 500;
+
+/// Periodically tidies up the memory store: forgets memories that have fully decayed,
+/// and merges clusters of near-duplicate memories into one.
+/// Memories are read from the store, but written through the writer, so they stay in order with every other write.
+pub struct MemoryConsolidator {
+    memory_store: Arc<dyn MemoryStore + Send + Sync>,
+    memory_writer: MemoryWriter,
+    model_client: Box<dyn ModelClient + Send + Sync>,
+    extract_facts: bool,
+}
+
+struct Entry {
+    id: String,
+    document: String,
+    metadata: HashMap<String, String>,
+    embedding: Vec<f32>,
+}
+
+/// Near-duplicate memories, newest first. Later memories join it by being like its newest.
+struct Cluster {
+    representative: Vec<f32>,
+    members: Vec<Entry>,
+}
+
+impl Entry {
+    fn timestamp(&self, key: &str) -> u64 {
+        self.metadata
+            .get(key)
+            .and_then(|t| t.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl MemoryConsolidator {
+    /// If `extract_facts` is set, each cluster of duplicates is replaced by the durable facts
+    /// the model finds in it. Otherwise, only the newest memory of each cluster is kept.
+    pub fn new(
+        memory_store: Arc<dyn MemoryStore + Send + Sync>,
+        memory_writer: MemoryWriter,
+        model_client: Box<dyn ModelClient + Send + Sync>,
+        extract_facts: bool,
+    ) -> Self {
+        Self {
+            memory_store,
+            memory_writer,
+            model_client,
+            extract_facts,
+        }
+    }
+
+    /// Consolidates once every `interval`, forever.
+    pub async fn run(self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+            self.consolidate().await;
+        }
+    }
+
+    pub async fn consolidate(&self) {
+        info!("Consolidating memories...");
+
+        let now = unix_timestamp();
+        let mut to_delete = Vec::<String>::new();
+
+        // Memories are only ever merged with others from the same user:
+        let mut clusters_by_user = HashMap::<String, Vec<Cluster>>::new();
+
+        // Memories written while listing shift the pages along, so some may be listed twice:
+        let mut seen = HashSet::<String>::new();
+
+        let mut offset = 0;
+        loop {
+            let page = match self.list_page(offset).await {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Failed to list memories to consolidate: {e}");
+                    return;
+                }
+            };
+            let page_len = page.len();
+            offset += page_len;
+
+            for entry in page {
+                if !seen.insert(entry.id.clone()) {
+                    continue;
+                }
+
+                if decay(&entry.metadata, now) < MIN_RETAINED_WEIGHT {
+                    debug!("Forgetting decayed memory: {}", entry.document);
+                    to_delete.push(entry.id);
+                    continue;
+                }
+
+                let user_id = entry
+                    .metadata
+                    .get(METADATA_USER_ID)
+                    .cloned()
+                    .unwrap_or_default();
+                add_to_clusters(clusters_by_user.entry(user_id).or_default(), entry);
+            }
+
+            if page_len < PAGE_SIZE {
+                break;
+            }
+        }
+
+        for (user_id, clusters) in clusters_by_user {
+            for cluster in clusters {
+                if cluster.members.len() > 1 {
+                    debug!(
+                        "Merging {} similar memories for user {user_id}",
+                        cluster.members.len()
+                    );
+                    to_delete.extend(self.merge(cluster.members).await);
+                }
+            }
+        }
+
+        if !to_delete.is_empty() {
+            info!("Consolidation removed {} memories", to_delete.len());
+            let request = MemoryDeleteRequest::by_ids(to_delete);
+            if let Err(e) = self.memory_writer.delete(request).await {
+                error!("Failed to delete consolidated memories: {e}");
+            }
+        }
+
+        info!("...Done consolidating memories.");
+    }
+
+    /// Up to `PAGE_SIZE` memories, newest first, starting `offset` memories in, each with its embedding.
+    /// Embeddings are the store's own where it has them, so memories aren't embedded again every time.
+    async fn list_page(&self, offset: usize) -> Result<Vec<Entry>, Box<dyn Error + Send + Sync>> {
+        let response = self
+            .memory_store
+            .list(&MemoryListRequest {
+                limit: PAGE_SIZE,
+                offset,
+                include_embeddings: true,
+                ..Default::default()
+            })
+            .await?;
+
+        let embeddings = if response.embeddings.len() == response.ids.len() {
+            response.embeddings
+        } else {
+            debug!("The memory store gave no embeddings, so embedding memories to compare them");
+
+            let input = response
+                .documents
+                .iter()
+                .map(|document| format!("passage: {document}"))
+                .collect();
+
+            let mut embeddings = self
+                .model_client
+                .request_embeddings(&EmbeddingsRequest::new(input))
+                .await
+                .take_embeddings();
+            embeddings.sort_unstable_by_key(Embedding::index);
+
+            embeddings
+                .into_iter()
+                .map(|embedding| embedding.embedding().to_vec())
+                .collect()
+        };
+
+        Ok(response
+            .ids
+            .into_iter()
+            .zip(response.documents)
+            .zip(response.metadatas)
+            .zip(embeddings)
+            .map(|(((id, document), metadata), embedding)| Entry {
+                id,
+                document,
+                metadata: serde_json::from_str(&metadata).unwrap_or_default(),
+                embedding,
+            })
+            .collect())
+    }
+
+    /// Collapses a cluster into a single memory, returning the ids that should be deleted.
+    async fn merge(&self, cluster: Vec<Entry>) -> Vec<String> {
+        let reinforced_at = cluster
+            .iter()
+            .map(|e| e.timestamp(METADATA_REINFORCED_AT))
+            .max()
+            .unwrap_or_default();
+
+        if !self.extract_facts {
+            let mut cluster = cluster.into_iter();
+            let newest = cluster.next().expect("Clusters are never empty");
+
+            // Keep the newest, but make sure it's as fresh as the freshest duplicate:
+            let mut metadata = newest.metadata;
+            metadata.insert(METADATA_REINFORCED_AT.to_owned(), reinforced_at.to_string());
+
+            let mut request = MemoryStoreRequest::new();
+            request.add_document(newest.id, newest.document, metadata);
+            self.memory_writer.update(request);
+
+            return cluster.map(|e| e.id).collect();
+        }
+
+        let documents: Vec<&str> = cluster.iter().map(|e| e.document.as_str()).collect();
+        let facts = self.extract_facts(&documents).await;
+        let newest = &cluster[0];
+
+        if let Some(facts) = facts {
+            let mut metadata = HashMap::from([
+                (METADATA_TIMESTAMP.to_owned(), unix_timestamp().to_string()),
+                (
+                    METADATA_SOURCE.to_owned(),
+                    MemorySource::Consolidated.as_str().to_owned(),
+                ),
+                (METADATA_REINFORCED_AT.to_owned(), reinforced_at.to_string()),
+            ]);
+            for key in [METADATA_USER_ID, METADATA_SESSION_ID] {
+                if let Some(value) = newest.metadata.get(key) {
+                    metadata.insert(key.to_owned(), value.clone());
+                }
+            }
+
+            let mut request = MemoryStoreRequest::new();
+            // Empty ID value is ok, the writer will generate one:
+            request.add_document("", facts, metadata);
+            if let Err(e) = self.memory_writer.store_and_wait(request).await {
+                // Keep the originals, rather than lose what they knew:
+                error!("Failed to store consolidated memory: {e}");
+                return Vec::new();
+            }
+        }
+
+        // With or without facts worth keeping, every original is replaced:
+        cluster.into_iter().map(|e| e.id).collect()
+    }
+
+    /// Asks the model for the durable facts in `documents`, or `None` if there are none.
+    async fn extract_facts(&self, documents: &[&str]) -> Option<String> {
+        let prompt = load_prompt_text("memory_consolidate.txt");
+        let request = GuidanceRequestBuilder::new(prompt)
+            .with_parameter_list("memories", documents)
+            .build();
+
+        let response = self.model_client.request_guidance(&request).await;
+        let facts = response.expect_variable("facts").trim();
+
+        if facts.is_empty() || facts == "NONE" {
+            None
+        } else {
+            Some(facts.to_owned())
+        }
+    }
+}
+
+/// Greedily, as memories come newest first, each joins the first cluster whose newest member it duplicates.
+fn add_to_clusters(clusters: &mut Vec<Cluster>, entry: Entry) {
+    let existing = clusters.iter_mut().find(|cluster| {
+        cosine_similarity(&cluster.representative, &entry.embedding) >= DUPLICATE_SIMILARITY
+    });
+
+    match existing {
+        Some(cluster) => cluster.members.push(entry),
+        None => clusters.push(Cluster {
+            representative: entry.embedding.clone(),
+            members: vec![entry],
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::{generate_id, keyword_embedder::KeywordEmbedder, LocalMemoryStore},
+        model_client::MemoryListRequest,
+    };
+
+    #[test]
+    fn decays_by_half_every_month_since_last_touched() {
+        let month = 30 * 24 * 60 * 60;
+        let written = |timestamp: u64, reinforced_at: Option<u64>| {
+            let mut metadata =
+                HashMap::from([(METADATA_TIMESTAMP.to_owned(), timestamp.to_string())]);
+            if let Some(reinforced_at) = reinforced_at {
+                metadata.insert(METADATA_REINFORCED_AT.to_owned(), reinforced_at.to_string());
+            }
+            metadata
+        };
+
+        let now = 100 * month;
+        assert!((decay(&written(now, None), now) - 1.0).abs() < 1e-6);
+        assert!((decay(&written(now - month, None), now) - 0.5).abs() < 1e-6);
+        assert!((decay(&written(now - 2 * month, None), now) - 0.25).abs() < 1e-6);
+        assert!((decay(&written(0, Some(now - month)), now) - 0.5).abs() < 1e-6);
+        assert!(decay(&written(now - 7 * month, None), now) < MIN_RETAINED_WEIGHT);
+        assert!((decay(&HashMap::new(), now) - 1.0).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn forgets_decayed_memories_and_keeps_the_newest_of_each_users_duplicates() {
+        let path = std::env::temp_dir().join(format!("rainchain-memory-{}.json", generate_id()));
+        let store: Arc<dyn MemoryStore + Send + Sync> =
+            Arc::new(LocalMemoryStore::open(&path, Box::new(KeywordEmbedder::default())).unwrap());
+        let writer = MemoryWriter::spawn(store.clone());
+
+        let now = unix_timestamp();
+        let mut request = MemoryStoreRequest::new();
+        for (id, user_id, document, timestamp, reinforced_at) in [
+            ("newest-cat", "a", "USER: my cat", now - 10, 0),
+            ("older-cat", "a", "USER: my cat, my cat", now - 20, now - 5),
+            ("dog", "a", "USER: my dog", now - 30, 0),
+            ("decayed", "a", "USER: rain", 1, 0),
+            ("other-users-cat", "b", "USER: a cat", now - 40, 0),
+        ] {
+            let metadata = HashMap::from([
+                (METADATA_USER_ID.to_owned(), user_id.to_owned()),
+                (METADATA_TIMESTAMP.to_owned(), timestamp.to_string()),
+                (METADATA_REINFORCED_AT.to_owned(), reinforced_at.to_string()),
+            ]);
+            request.add_document(id, document, metadata);
+        }
+        store.store(&request).await.unwrap();
+
+        let consolidator = MemoryConsolidator::new(
+            store.clone(),
+            writer.clone(),
+            Box::new(KeywordEmbedder::default()),
+            false,
+        );
+        consolidator.consolidate().await;
+        writer.flush().await;
+
+        let left = store
+            .list(&MemoryListRequest {
+                limit: 10,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(left.ids, ["newest-cat", "dog", "other-users-cat"]);
+
+        // The kept duplicate is as fresh as the freshest one it replaced:
+        let kept: HashMap<String, String> = serde_json::from_str(&left.metadatas[0]).unwrap();
+        assert_eq!(kept[METADATA_REINFORCED_AT], (now - 5).to_string());
+
+        let _ = std::fs::remove_file(path);
+    }
+}
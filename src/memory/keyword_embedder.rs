@@ -0,0 +1,93 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use futures_util::Stream;
+use serde_json::json;
+
+use crate::model_client::{
+    EmbeddingsRequest, EmbeddingsResponse, GuidanceRequest, GuidanceResponse, MemoryGetRequest,
+    MemoryGetResponse, MemoryStoreRequest, ModelClient, RerankRequest, RerankResponse,
+};
+
+/// The words a [`KeywordEmbedder`] embeds by.
+const KEYWORDS: [&str; 4] = ["cat", "dog", "rain", "tea"];
+
+/// Embeds text as how often it uses each of a few keywords, so tests can tell what is similar to what.
+/// Any other request fails the test.
+#[derive(Default)]
+pub struct KeywordEmbedder {
+    /// How many fewer embeddings than asked for to send back.
+    pub short_by: usize,
+}
+
+pub fn keyword_embedding(text: &str) -> Vec<f32> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .collect();
+
+    KEYWORDS
+        .iter()
+        .map(|keyword| {
+            #[allow(clippy::cast_precision_loss)]
+            let count = words.iter().filter(|word| word == keyword).count() as f32;
+            count
+        })
+        .collect()
+}
+
+#[async_trait]
+impl ModelClient for KeywordEmbedder {
+    async fn request_embeddings(&self, request: &EmbeddingsRequest) -> EmbeddingsResponse {
+        let count = request.input.len().saturating_sub(self.short_by);
+        let data = request
+            .input
+            .iter()
+            .take(count)
+            .enumerate()
+            .map(|(index, text)| {
+                serde_json::from_value(json!({
+                    "object": "embedding",
+                    "embedding": keyword_embedding(text),
+                    "index": index,
+                }))
+                .unwrap()
+            })
+            .collect();
+
+        EmbeddingsResponse {
+            object: String::from("list"),
+            data,
+            model: String::from("keywords"),
+        }
+    }
+
+    async fn request_memory(&self, _request: &MemoryGetRequest) -> MemoryGetResponse {
+        unimplemented!("Not an embedding request")
+    }
+
+    async fn store_memory(
+        &self,
+        _request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        unimplemented!("Not an embedding request")
+    }
+
+    async fn request_guidance(&self, _request: &GuidanceRequest) -> GuidanceResponse {
+        unimplemented!("Not an embedding request")
+    }
+
+    async fn request_rerank(
+        &self,
+        _request: &RerankRequest,
+    ) -> Result<RerankResponse, Box<dyn Error + Send + Sync>> {
+        unimplemented!("Not an embedding request")
+    }
+
+    fn request_guidance_stream(
+        &self,
+        _request: &GuidanceRequest,
+    ) -> Box<dyn Stream<Item = Option<GuidanceResponse>> + Send + Unpin> {
+        unimplemented!("Not an embedding request")
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    model_client::{
+        Embedding, EmbeddingsRequest, MemoryDeleteRequest, MemoryGetRequest, MemoryGetResponse,
+        MemoryListRequest, MemoryStoreRequest, ModelClient,
+    },
+    tools::web_search::cosine_similarity,
+};
+
+use super::{generate_id, matches_filter, MemoryStore, METADATA_TIMESTAMP};
+
+/// How many memories a query returns, at most.
+const TOP_K: usize = 5;
+
+/// Memory kept in-process, and persisted to a JSON file on disk after every write.
+/// Embeddings come from the model client, so any backend that can embed text can be used.
+pub struct LocalMemoryStore {
+    path: PathBuf,
+    model_client: Box<dyn ModelClient + Send + Sync>,
+    records: Mutex<Vec<MemoryRecord>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MemoryRecord {
+    id: String,
+    document: String,
+    metadata: HashMap<String, String>,
+    embedding: Vec<f32>,
+}
+
+impl LocalMemoryStore {
+    /// Opens the store at `path`, loading any memories already saved there.
+    /// Fails if there is a file there that can't be read as memories, rather than overwrite it.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        model_client: Box<dyn ModelClient + Send + Sync>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let path = path.into();
+        let records = load_records(&path)?;
+
+        info!(
+            "Opened local memory store at {} with {} memories",
+            path.display(),
+            records.len()
+        );
+
+        Ok(Self {
+            path,
+            model_client,
+            records: Mutex::new(records),
+        })
+    }
+
+    /// Embeds each of `input`, in order. Fails unless the model sends back an embedding for every one.
+    async fn embed(
+        &self,
+        input: Vec<String>,
+    ) -> Result<Vec<Embedding>, Box<dyn Error + Send + Sync>> {
+        let count = input.len();
+        let response = self
+            .model_client
+            .request_embeddings(&EmbeddingsRequest::new(input))
+            .await;
+
+        let mut embeddings = response.take_embeddings();
+        embeddings.sort_unstable_by_key(Embedding::index);
+
+        if embeddings.len() != count {
+            return Err(
+                format!("Asked for {count} embeddings, but got {}", embeddings.len()).into(),
+            );
+        }
+
+        Ok(embeddings)
+    }
+
+    async fn persist(&self, records: &[MemoryRecord]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let json = serde_json::to_string_pretty(records)?;
+
+        // Write to the side and then swap in, so a crash mid-write never corrupts the store:
+        let temp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&temp_path, json).await?;
+        tokio::fs::rename(&temp_path, &self.path).await?;
+
+        debug!(
+            "Persisted {} memories to {}",
+            records.len(),
+            self.path.display()
+        );
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MemoryStore for LocalMemoryStore {
+    async fn store(
+        &self,
+        request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let documents: Vec<String> = request
+            .entries()
+            .map(|(_, document, _)| format!("passage: {document}"))
+            .collect();
+
+        if documents.is_empty() {
+            return Ok(());
+        }
+
+        let embeddings = self.embed(documents).await?;
+
+        let mut records = self.records.lock().await;
+
+        for ((id, document, metadata), embedding) in request.entries().zip(embeddings) {
+            // Same as the remote store, an empty id means one should be generated:
+            let id = if id.is_empty() {
+                generate_id()
+            } else {
+                id.to_owned()
+            };
+
+            records.retain(|record| record.id != id);
+            records.push(MemoryRecord {
+                id,
+                document: document.to_owned(),
+                metadata: metadata.clone(),
+                embedding: embedding.embedding().to_vec(),
+            });
+        }
+
+        self.persist(&records).await
+    }
+
+    async fn query(&self, request: &MemoryGetRequest) -> MemoryGetResponse {
+        let embeddings = match self.embed(vec![format!("query: {}", request.query)]).await {
+            Ok(embeddings) => embeddings,
+            Err(e) => {
+                warn!("Could not embed memory query, so recalling nothing: {e}");
+                return MemoryGetResponse::default();
+            }
+        };
+        // One for the one input:
+        let query_embedding = &embeddings[0];
+
+        let records = self.records.lock().await;
+
+        let mut with_scores: Vec<(&MemoryRecord, OrderedFloat<f32>)> = records
+            .iter()
+            .filter(|r| matches_filter(&r.metadata, &request.filter))
+            .map(|r| {
+                let similarity = cosine_similarity(query_embedding.embedding(), &r.embedding);
+                (r, OrderedFloat(similarity))
+            })
+            .collect();
+
+        with_scores.sort_unstable_by_key(|(_, score)| -*score);
+
+        let mut response = MemoryGetResponse::default();
+
+        for (record, similarity) in with_scores.into_iter().take(TOP_K) {
+            response.ids.push(record.id.clone());
+            // Report cosine distance, so smaller means closer, same as the remote store:
+            response.distances.push(1.0 - similarity.into_inner());
+            response.metadatas.push(
+                serde_json::to_string(&record.metadata).expect("Could not serialize metadata"),
+            );
+            response.documents.push(record.document.clone());
+        }
+
+        response
+    }
+
+    async fn list(
+        &self,
+        request: &MemoryListRequest,
+    ) -> Result<MemoryGetResponse, Box<dyn Error + Send + Sync>> {
+        let records = self.records.lock().await;
+
+        let mut matching: Vec<&MemoryRecord> = records
+            .iter()
+            .filter(|r| request.ids.is_empty() || request.ids.contains(&r.id))
+            .filter(|r| matches_filter(&r.metadata, &request.filter))
+            .collect();
+
+        // Newest first:
+        matching.sort_by_key(|r| std::cmp::Reverse(timestamp(r)));
+
+        let mut response = MemoryGetResponse::default();
+
+        for record in matching
+            .into_iter()
+            .skip(request.offset)
+            .take(request.limit)
+        {
+            response.ids.push(record.id.clone());
+            response.metadatas.push(
+                serde_json::to_string(&record.metadata).expect("Could not serialize metadata"),
+            );
+            response.documents.push(record.document.clone());
+
+            if request.include_embeddings {
+                response.embeddings.push(record.embedding.clone());
+            }
+        }
+
+        Ok(response)
+    }
+
+    async fn update(
+        &self,
+        request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Only a changed document needs a new embedding:
+        let changed: Vec<(&str, &str)> = {
+            let records = self.records.lock().await;
+
+            request
+                .entries()
+                .filter(|(id, document, _)| {
+                    records
+                        .iter()
+                        .any(|r| r.id == *id && r.document != *document)
+                })
+                .map(|(id, document, _)| (id, document))
+                .collect()
+        };
+
+        let embeddings = if changed.is_empty() {
+            Vec::new()
+        } else {
+            self.embed(
+                changed
+                    .iter()
+                    .map(|(_, document)| format!("passage: {document}"))
+                    .collect(),
+            )
+            .await?
+        };
+        let mut new_embeddings: HashMap<&str, &[f32]> = changed
+            .iter()
+            .zip(&embeddings)
+            .map(|((id, _), embedding)| (*id, embedding.embedding()))
+            .collect();
+
+        let mut records = self.records.lock().await;
+
+        for (id, document, metadata) in request.entries() {
+            let Some(record) = records.iter_mut().find(|r| r.id == id) else {
+                warn!("No memory with id '{id}' to update");
+                continue;
+            };
+
+            if let Some(embedding) = new_embeddings.remove(id) {
+                document.clone_into(&mut record.document);
+                record.embedding = embedding.to_vec();
+            }
+            record.metadata.clone_from(metadata);
+        }
+
+        self.persist(&records).await
+    }
+
+    async fn delete(
+        &self,
+        request: &MemoryDeleteRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut records = self.records.lock().await;
+        let count_before = records.len();
+
+        if request.ids.is_empty() && request.filter.is_empty() {
+            warn!("Refusing to delete with neither ids nor a filter");
+            return Ok(());
+        }
+
+        records.retain(|r| {
+            let by_id = request.ids.is_empty() || request.ids.contains(&r.id);
+
+            !(by_id && matches_filter(&r.metadata, &request.filter))
+        });
+
+        info!("Deleted {} memories", count_before - records.len());
+
+        self.persist(&records).await
+    }
+}
+
+fn timestamp(record: &MemoryRecord) -> u64 {
+    record
+        .metadata
+        .get(METADATA_TIMESTAMP)
+        .and_then(|t| t.parse().ok())
+        .unwrap_or_default()
+}
+
+fn load_records(path: &Path) -> Result<Vec<MemoryRecord>, Box<dyn Error + Send + Sync>> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!(
+                "No memory store found at {}; starting empty",
+                path.display()
+            );
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(format!("Could not read {}: {e}", path.display()).into()),
+    };
+
+    serde_json::from_str(&json).map_err(|e| {
+        format!(
+            "{} is not a memory store ({e}). Move it out of the way to start a new one.",
+            path.display()
+        )
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{keyword_embedder::KeywordEmbedder, METADATA_USER_ID};
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("rainchain-memory-{}.json", generate_id()))
+    }
+
+    fn open(path: &Path) -> LocalMemoryStore {
+        LocalMemoryStore::open(path, Box::new(KeywordEmbedder::default())).unwrap()
+    }
+
+    fn metadata(user_id: &str, timestamp: u64) -> HashMap<String, String> {
+        HashMap::from([
+            (METADATA_USER_ID.to_owned(), user_id.to_owned()),
+            (METADATA_TIMESTAMP.to_owned(), timestamp.to_string()),
+        ])
+    }
+
+    /// A store holding `documents`, each by user "a" unless it mentions tea, written a second apart in order.
+    async fn store_with(path: &Path, documents: &[&str]) -> LocalMemoryStore {
+        let store = open(path);
+
+        let mut request = MemoryStoreRequest::new();
+        for (timestamp, document) in (1..).zip(documents) {
+            let user_id = if document.contains("tea") { "b" } else { "a" };
+            request.add_document(
+                format!("id-{timestamp}"),
+                *document,
+                metadata(user_id, timestamp),
+            );
+        }
+        store.store(&request).await.unwrap();
+
+        store
+    }
+
+    fn query(text: &str, user_id: &str) -> MemoryGetRequest {
+        MemoryGetRequest {
+            query: text.to_owned(),
+            filter: HashMap::from([(METADATA_USER_ID.to_owned(), user_id.to_owned())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn queries_return_the_closest_matches_of_the_users_own_memories() {
+        let path = temp_path();
+        let store = store_with(
+            &path,
+            &[
+                "dog",
+                "cat cat",
+                "rain",
+                "cat dog",
+                "cat tea",
+                "cat rain dog",
+            ],
+        )
+        .await;
+
+        let response = store.query(&query("cat", "a")).await;
+
+        assert_eq!(response.ids.len(), TOP_K);
+        assert_eq!(response.ids[..3], ["id-2", "id-4", "id-6"]);
+        assert!(response.distances.windows(2).all(|d| d[0] <= d[1]));
+
+        let response = store.query(&query("cat", "b")).await;
+        assert_eq!(response.documents, ["cat tea"]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn lists_newest_first_from_the_offset() {
+        let path = temp_path();
+        let store = store_with(&path, &["one", "two", "three tea", "four", "five"]).await;
+
+        let page = |offset, limit| MemoryListRequest {
+            filter: HashMap::from([(METADATA_USER_ID.to_owned(), String::from("a"))]),
+            limit,
+            offset,
+            ..Default::default()
+        };
+
+        let first = store.list(&page(0, 2)).await.unwrap();
+        assert_eq!(first.documents, ["five", "four"]);
+
+        let rest = store.list(&page(2, 10)).await.unwrap();
+        assert_eq!(rest.documents, ["two", "one"]);
+
+        let by_id = MemoryListRequest {
+            ids: vec![String::from("id-3"), String::from("id-4")],
+            limit: 10,
+            ..Default::default()
+        };
+        let listed = store.list(&by_id).await.unwrap();
+        assert_eq!(listed.documents, ["four", "three tea"]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn updates_and_deletes_only_what_matches() {
+        let path = temp_path();
+        let store = store_with(&path, &["dog", "rain", "cat tea"]).await;
+
+        let mut update = MemoryStoreRequest::new();
+        update.add_document("id-1", "cat", metadata("a", 4));
+        store.update(&update).await.unwrap();
+
+        let response = store.query(&query("cat", "a")).await;
+        assert_eq!(response.documents[0], "cat");
+        assert!(response.distances[0] < 0.01);
+
+        // Ids and filter must both match, so the other user's memory stays:
+        let delete = MemoryDeleteRequest {
+            ids: vec![String::from("id-1"), String::from("id-3")],
+            filter: HashMap::from([(METADATA_USER_ID.to_owned(), String::from("a"))]),
+        };
+        store.delete(&delete).await.unwrap();
+
+        let everything = MemoryListRequest {
+            limit: 10,
+            ..Default::default()
+        };
+        let left = store.list(&everything).await.unwrap();
+        assert_eq!(left.documents, ["cat tea", "rain"]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn memories_survive_reopening() {
+        let path = temp_path();
+        store_with(&path, &["dog", "cat"]).await;
+
+        let reopened = open(&path);
+        let response = reopened.query(&query("cat", "a")).await;
+
+        assert_eq!(response.ids, ["id-2", "id-1"]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn fails_rather_than_losing_memories() {
+        let path = temp_path();
+        std::fs::write(&path, "not json").unwrap();
+        assert!(
+            LocalMemoryStore::open(&path, Box::new(KeywordEmbedder::default())).is_err(),
+            "A corrupt store should not be opened, or it would be overwritten"
+        );
+        let _ = std::fs::remove_file(&path);
+
+        let store =
+            LocalMemoryStore::open(&path, Box::new(KeywordEmbedder { short_by: 1 })).unwrap();
+
+        let mut request = MemoryStoreRequest::new();
+        request.add_document("", "cat", HashMap::new());
+        request.add_document("", "dog", HashMap::new());
+
+        assert!(store.store(&request).await.is_err());
+        assert!(store.query(&query("cat", "a")).await.ids.is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+
+use crate::model_client::{
+    MemoryDeleteRequest, MemoryGetRequest, MemoryGetResponse, MemoryListRequest,
+    MemoryStoreRequest, ModelClient,
+};
+
+use super::MemoryStore;
+
+/// Memory kept by the model server itself, via its `/memory` route.
+/// The server can only store memories and find them by meaning, so they can't be listed, updated or deleted.
+pub struct RemoteMemoryStore {
+    model_client: Box<dyn ModelClient + Send + Sync>,
+}
+
+impl RemoteMemoryStore {
+    pub fn new(model_client: Box<dyn ModelClient + Send + Sync>) -> Self {
+        Self { model_client }
+    }
+}
+
+#[async_trait]
+impl MemoryStore for RemoteMemoryStore {
+    async fn store(
+        &self,
+        request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.model_client.store_memory(request).await
+    }
+
+    async fn query(&self, request: &MemoryGetRequest) -> MemoryGetResponse {
+        self.model_client.request_memory(request).await
+    }
+
+    async fn list(
+        &self,
+        _request: &MemoryListRequest,
+    ) -> Result<MemoryGetResponse, Box<dyn Error + Send + Sync>> {
+        Err("The model server can't list memories. Set RAINCHAIN_MEMORY=local to keep them in a store that can.".into())
+    }
+
+    fn can_manage(&self) -> bool {
+        false
+    }
+
+    async fn update(
+        &self,
+        _request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Err("The model server can't update memories. Set RAINCHAIN_MEMORY=local to keep them in a store that can.".into())
+    }
+
+    async fn delete(
+        &self,
+        _request: &MemoryDeleteRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Err("The model server can't delete memories. Set RAINCHAIN_MEMORY=local to keep them in a store that can.".into())
+    }
+}
@@ -0,0 +1,285 @@
+use std::{error::Error, sync::Arc, time::Duration};
+
+use log::{debug, error, info, warn};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::model_client::{MemoryDeleteRequest, MemoryStoreRequest};
+
+use super::MemoryStore;
+
+/// Writes beyond this many waiting in the queue are dropped, rather than slowing down turns.
+const QUEUE_CAPACITY: usize = 256;
+
+/// At most this many queued writes are combined into one request to the store.
+const MAX_BATCH_SIZE: usize = 32;
+
+const MAX_ATTEMPTS: u32 = 3;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+enum Write {
+    Store(MemoryStoreRequest),
+
+    /// Done once every write queued before it is, answering whether it worked.
+    StoreAndWait(MemoryStoreRequest, oneshot::Sender<Result<(), String>>),
+    Update(MemoryStoreRequest),
+
+    /// Done once every write queued before it is, so nothing it deletes can be written back afterward.
+    Delete(MemoryDeleteRequest, oneshot::Sender<Result<(), String>>),
+
+    /// Sent on shutdown. Everything queued before it has been written once it is acknowledged.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Queues memory writes for a background worker, so callers never wait on the memory store.
+/// Queued writes are batched together, and retried if they fail.
+/// Every memory stored through it gets an id first, so that a retried store can't store it twice.
+/// Cheap to clone; all clones feed the same worker.
+#[derive(Clone)]
+pub struct MemoryWriter {
+    sender: mpsc::Sender<Write>,
+}
+
+impl MemoryWriter {
+    /// Starts the background worker that writes to `memory_store`.
+    pub fn spawn(memory_store: Arc<dyn MemoryStore + Send + Sync>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+
+        tokio::spawn(run_worker(memory_store, receiver));
+
+        Self { sender }
+    }
+
+    pub fn store(&self, mut request: MemoryStoreRequest) {
+        request.assign_missing_ids();
+        self.enqueue(Write::Store(request));
+    }
+
+    /// Stores `request` after any writes already queued, and waits until it is done.
+    pub async fn store_and_wait(
+        &self,
+        mut request: MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        request.assign_missing_ids();
+        let (sender, receiver) = oneshot::channel();
+
+        self.sender
+            .send(Write::StoreAndWait(request, sender))
+            .await
+            .map_err(|_| "The memory writer has stopped")?;
+
+        receiver
+            .await
+            .map_err(|_| "The memory writer has stopped")?
+            .map_err(Into::into)
+    }
+
+    pub fn update(&self, request: MemoryStoreRequest) {
+        self.enqueue(Write::Update(request));
+    }
+
+    /// Deletes the memories `request` matches, after any writes already queued, and waits until it is done.
+    pub async fn delete(
+        &self,
+        request: MemoryDeleteRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (sender, receiver) = oneshot::channel();
+
+        // Unlike other writes, deletes are never dropped, as the user is waiting to hear they happened:
+        self.sender
+            .send(Write::Delete(request, sender))
+            .await
+            .map_err(|_| "The memory writer has stopped")?;
+
+        receiver
+            .await
+            .map_err(|_| "The memory writer has stopped")?
+            .map_err(Into::into)
+    }
+
+    fn enqueue(&self, write: Write) {
+        if let Err(e) = self.sender.try_send(write) {
+            warn!("Dropping memory write: {e}");
+        }
+    }
+
+    /// Waits for every write queued so far to finish.
+    pub async fn flush(&self) {
+        let (sender, receiver) = oneshot::channel();
+
+        if self.sender.send(Write::Flush(sender)).await.is_ok() {
+            let _ = receiver.await;
+        }
+    }
+}
+
+async fn run_worker(
+    memory_store: Arc<dyn MemoryStore + Send + Sync>,
+    mut receiver: mpsc::Receiver<Write>,
+) {
+    info!("Memory writer started");
+
+    while let Some(first) = receiver.recv().await {
+        // Take whatever else is already waiting, so it can be written together:
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH_SIZE {
+            let Ok(next) = receiver.try_recv() else {
+                break;
+            };
+            batch.push(next);
+        }
+
+        debug!("Writing a batch of {} memory writes", batch.len());
+
+        let mut stores = MemoryStoreRequest::new();
+        let mut updates = MemoryStoreRequest::new();
+        let mut flushes = Vec::new();
+
+        for write in batch {
+            match write {
+                Write::Store(request) => stores.append(request),
+                Write::StoreAndWait(request, done) => {
+                    write_pending(memory_store.as_ref(), &mut stores, &mut updates).await;
+
+                    let result = with_retries("store", || memory_store.store(&request)).await;
+                    let _ = done.send(result.map_err(|e| e.to_string()));
+                }
+                Write::Update(request) => updates.append(request),
+                Write::Delete(request, done) => {
+                    write_pending(memory_store.as_ref(), &mut stores, &mut updates).await;
+
+                    let result = with_retries("delete", || memory_store.delete(&request)).await;
+                    let _ = done.send(result.map_err(|e| e.to_string()));
+                }
+                Write::Flush(ack) => flushes.push(ack),
+            }
+        }
+
+        write_pending(memory_store.as_ref(), &mut stores, &mut updates).await;
+
+        for ack in flushes {
+            let _ = ack.send(());
+        }
+    }
+
+    info!("Memory writer stopped");
+}
+
+/// Writes, and empties, the stores and updates gathered so far.
+async fn write_pending(
+    memory_store: &(dyn MemoryStore + Send + Sync),
+    stores: &mut MemoryStoreRequest,
+    updates: &mut MemoryStoreRequest,
+) {
+    let stores = std::mem::take(stores);
+    let updates = std::mem::take(updates);
+
+    if !stores.is_empty() {
+        let _ = with_retries("store", || memory_store.store(&stores)).await;
+    }
+
+    if !updates.is_empty() {
+        let _ = with_retries("update", || memory_store.update(&updates)).await;
+    }
+}
+
+/// Runs `write` until it succeeds, backing off between attempts. Gives up after [`MAX_ATTEMPTS`], returning the last error.
+/// Only for writes that are safe to repeat, as a write that timed out may have happened anyway.
+async fn with_retries<F, Fut>(name: &str, write: F) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<(), Box<dyn Error + Send + Sync>>>,
+{
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        match write().await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                warn!("Memory {name} failed (attempt {attempt}), retrying in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("Memory {name} failed after {attempt} attempts, giving up: {e}");
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::model_client::{MemoryGetRequest, MemoryGetResponse, MemoryListRequest};
+
+    /// Records the order writes reach the store in.
+    #[derive(Default)]
+    struct RecordingStore {
+        writes: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl MemoryStore for RecordingStore {
+        async fn store(
+            &self,
+            request: &MemoryStoreRequest,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            for (id, _, _) in request.entries() {
+                assert!(!id.is_empty(), "Every stored memory should have an id");
+                self.writes.lock().unwrap().push(String::from("store"));
+            }
+
+            Ok(())
+        }
+
+        async fn query(&self, _request: &MemoryGetRequest) -> MemoryGetResponse {
+            MemoryGetResponse::default()
+        }
+
+        async fn list(
+            &self,
+            _request: &MemoryListRequest,
+        ) -> Result<MemoryGetResponse, Box<dyn Error + Send + Sync>> {
+            Ok(MemoryGetResponse::default())
+        }
+
+        async fn update(
+            &self,
+            _request: &MemoryStoreRequest,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.writes.lock().unwrap().push(String::from("update"));
+            Ok(())
+        }
+
+        async fn delete(
+            &self,
+            _request: &MemoryDeleteRequest,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.writes.lock().unwrap().push(String::from("delete"));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_after_the_writes_queued_before_them() {
+        let store = Arc::new(RecordingStore::default());
+        let writer = MemoryWriter::spawn(store.clone());
+
+        let mut request = MemoryStoreRequest::new();
+        request.add_document("", "USER: hi", HashMap::new());
+        writer.store(request);
+
+        writer
+            .delete(MemoryDeleteRequest::by_filter(HashMap::new()))
+            .await
+            .unwrap();
+
+        assert_eq!(*store.writes.lock().unwrap(), ["store", "delete"]);
+    }
+}
@@ -1,40 +1,94 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, error::Error};
 
 use async_trait::async_trait;
 use futures_util::Stream;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+use crate::memory::generate_id;
+
 #[async_trait]
 pub trait ModelClient {
     async fn request_embeddings(&self, request: &EmbeddingsRequest) -> EmbeddingsResponse;
     async fn request_memory(&self, request: &MemoryGetRequest) -> MemoryGetResponse;
-    async fn store_memory(&self, request: &MemoryStoreRequest);
+    async fn store_memory(
+        &self,
+        request: &MemoryStoreRequest,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
     async fn request_guidance(&self, request: &GuidanceRequest) -> GuidanceResponse;
+
+    /// Scores how well each document answers the query, with a cross-encoder on the backend.
+    async fn request_rerank(
+        &self,
+        request: &RerankRequest,
+    ) -> Result<RerankResponse, Box<dyn Error + Send + Sync>>;
     fn request_guidance_stream(
         &self,
         request: &GuidanceRequest,
     ) -> Box<dyn Stream<Item = Option<GuidanceResponse>> + Send + Unpin>;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct GuidanceEmbeddingsResponse {
-    pub object: String,
-    pub data: Vec<Embedding>,
-    pub model: String,
-}
-
 #[derive(Default, Serialize, Deserialize, Debug)]
 pub struct MemoryGetResponse {
     pub ids: Vec<String>,
     pub distances: Vec<f32>,
     pub metadatas: Vec<String>,
     pub documents: Vec<String>,
+
+    /// Each memory's stored embedding. Empty unless asked for, and the store can give them.
+    #[serde(default)]
+    pub embeddings: Vec<Vec<f32>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MemoryGetRequest {
     pub query: String,
+
+    /// Only memories whose metadata has exactly these values are returned.
+    #[serde(default)]
+    pub filter: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct MemoryListRequest {
+    /// Only memories with one of these ids are listed, unless it is empty.
+    #[serde(default)]
+    pub ids: Vec<String>,
+    pub filter: HashMap<String, String>,
+    pub limit: usize,
+
+    /// How many of the matching memories, newest first, to skip before listing any.
+    #[serde(default)]
+    pub offset: usize,
+
+    /// Whether to return each memory's stored embedding too.
+    #[serde(default)]
+    pub include_embeddings: bool,
+}
+
+/// Deletes the memories that have any of the given ids and match the filter.
+/// Both must match, so a filter narrows down the ids rather than adding to them.
+/// Empty ids match every memory, as does an empty filter, but at least one of the two must be given.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct MemoryDeleteRequest {
+    pub ids: Vec<String>,
+    pub filter: HashMap<String, String>,
+}
+
+impl MemoryDeleteRequest {
+    pub fn by_ids(ids: Vec<String>) -> Self {
+        Self {
+            ids,
+            ..Default::default()
+        }
+    }
+
+    pub fn by_filter(filter: HashMap<String, String>) -> Self {
+        Self {
+            filter,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -59,6 +113,33 @@ impl MemoryStoreRequest {
         self.documents.push(document.into());
         self.metadatas.push(metadatas);
     }
+
+    /// Adds every document from `other` after this request's own.
+    pub fn append(&mut self, other: MemoryStoreRequest) {
+        self.ids.extend(other.ids);
+        self.documents.extend(other.documents);
+        self.metadatas.extend(other.metadatas);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Gives every document without an id a newly generated one.
+    pub fn assign_missing_ids(&mut self) {
+        for id in self.ids.iter_mut().filter(|id| id.is_empty()) {
+            *id = generate_id();
+        }
+    }
+
+    /// Each document with its id and metadata, in the order they were added.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str, &HashMap<String, String>)> {
+        self.ids
+            .iter()
+            .zip(&self.documents)
+            .zip(&self.metadatas)
+            .map(|((id, document), metadata)| (id.as_str(), document.as_str(), metadata))
+    }
 }
 
 pub struct GuidanceRequestBuilder {
@@ -121,6 +202,11 @@ pub struct GuidanceRequest {
 pub struct GuidanceResponse {
     pub text: String,
     pub variables: HashMap<String, String>,
+
+    /// Log probabilities of each option considered by a `select`, keyed by the name given
+    /// to the select's `logprobs` argument, then by option.
+    #[serde(default)]
+    pub logprobs: HashMap<String, HashMap<String, f32>>,
 }
 
 impl GuidanceResponse {
@@ -138,6 +224,10 @@ impl GuidanceResponse {
         &self.text
     }
 
+    pub fn logprobs(&self, key: &str) -> Option<&HashMap<String, f32>> {
+        self.logprobs.get(key)
+    }
+
     pub fn apply_delta(&mut self, delta: GuidanceResponse) {
         self.text.push_str(delta.text());
 
@@ -148,6 +238,9 @@ impl GuidanceResponse {
                 self.variables.insert(k, v);
             }
         }
+
+        // Logprobs are only ever sent once a select completes, so they replace rather than append:
+        self.logprobs.extend(delta.logprobs);
     }
 
     pub fn new() -> Self {
@@ -192,6 +285,18 @@ impl GuidanceEmbeddingsRequestBuilder {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankRequest {
+    pub query: String,
+    pub documents: Vec<String>,
+}
+
+/// One score per document, in the order the documents were given. Higher is more relevant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankResponse {
+    pub scores: Vec<f32>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmbeddingsRequest {
     pub input: Vec<String>,
@@ -219,7 +324,8 @@ impl EmbeddingsResponse {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Embedding {
     object: String,
-    embedding: Vec<f32>,
+    #[serde(rename = "embedding")]
+    values: Vec<f32>,
     index: usize,
 }
 
@@ -229,6 +335,6 @@ impl Embedding {
     }
 
     pub fn embedding(&self) -> &[f32] {
-        self.embedding.as_ref()
+        self.values.as_ref()
     }
 }
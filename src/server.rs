@@ -22,12 +22,36 @@ pub trait SessionHandler {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MessageFromClient {
     message: String,
+
+    /// A secret the client keeps, so it can be recognised across sessions and its memories can follow it.
+    /// The server derives the user id from it, rather than taking an id from the client.
+    #[serde(default)]
+    user_token: Option<String>,
+
+    /// The user's answer, when this message replies to a confirmation request.
+    #[serde(default)]
+    confirmed: Option<bool>,
 }
 
 impl MessageFromClient {
     pub fn message(&self) -> &str {
         &self.message
     }
+
+    pub fn user_token(&self) -> Option<&str> {
+        self.user_token.as_deref()
+    }
+
+    /// This message's answer to a pending confirmation request: approved, denied, or `None` if it isn't an answer.
+    /// Without an explicit answer, a typed "yes" approves, a typed "no" denies, and anything else is some other message.
+    pub fn confirmation_answer(&self) -> Option<bool> {
+        self.confirmed
+            .or_else(|| match self.message.trim().to_lowercase().as_str() {
+                "y" | "yes" | "approve" | "ok" => Some(true),
+                "n" | "no" | "deny" | "cancel" => Some(false),
+                _ => None,
+            })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -1,75 +1,191 @@
+use std::{collections::VecDeque, sync::Arc};
+
 use async_trait::async_trait;
 use futures_util::StreamExt;
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use crate::{
-    agents::Agent,
+    agents::{Agent, AgentEvent},
+    cache::content_hash,
+    memory::{generate_id, MemoryScope, MemoryStore, MemoryWriter},
     server::{MessageChannel, MessageFromClient, MessageToClient, SessionHandler},
 };
 
+use self::memory_command::MemoryCommand;
+
+mod memory_command;
+
+/// Shorter user tokens could be guessed, and with them another user's memories.
+const MIN_USER_TOKEN_LEN: usize = 16;
+
 #[derive(Clone)]
 pub struct AgentSessionHandler<TAgent>
 where
-    TAgent: FnOnce() -> Box<dyn Agent + Send + Sync> + Send,
+    TAgent: FnOnce(MemoryScope) -> Box<dyn Agent + Send + Sync> + Send,
 {
     make_agent: TAgent,
+    memory_store: Arc<dyn MemoryStore + Send + Sync>,
+    memory_writer: MemoryWriter,
 }
 
 impl<TAgent> AgentSessionHandler<TAgent>
 where
-    TAgent: FnOnce() -> Box<dyn Agent + Send + Sync> + Send,
+    TAgent: FnOnce(MemoryScope) -> Box<dyn Agent + Send + Sync> + Send,
 {
-    pub fn new(make_agent: TAgent) -> Self {
-        Self { make_agent }
+    pub fn new(
+        make_agent: TAgent,
+        memory_store: Arc<dyn MemoryStore + Send + Sync>,
+        memory_writer: MemoryWriter,
+    ) -> Self {
+        Self {
+            make_agent,
+            memory_store,
+            memory_writer,
+        }
     }
 }
 
 #[async_trait]
 impl<TAgent> SessionHandler for AgentSessionHandler<TAgent>
 where
-    TAgent: FnOnce() -> Box<dyn Agent + Send + Sync> + Send,
+    TAgent: FnOnce(MemoryScope) -> Box<dyn Agent + Send + Sync> + Send,
 {
     async fn handle_session(self, mut ui_channel: impl MessageChannel + Send + Sync) {
-        info!("Session opened");
+        let session_id = generate_id();
+        info!("Session opened: {session_id}");
 
-        let mut agent = (self.make_agent)();
+        // The agent is made once we know who the user is, which is when their first message arrives:
+        let mut make_agent = Some(self.make_agent);
+        let mut agent = None;
+        let mut memory_scope = None;
+
+        // Messages that arrived while a turn was waiting on something else, to be handled once it's over:
+        let mut held_back = VecDeque::<MessageFromClient>::new();
 
         loop {
             // Get user's input:
             info!("Waiting for input from user...");
-            let user_input: String = {
+            let message: MessageFromClient = if let Some(message) = held_back.pop_front() {
+                message
+            } else {
                 let message = ui_channel.receive().await;
                 info!("got message:\n{message}");
-                let message: MessageFromClient = serde_json::from_str(&message).unwrap();
-                message.message().to_owned()
+                serde_json::from_str(&message).unwrap()
             };
+            let user_input = message.message();
             info!("Got input from user: {user_input}");
 
+            // The user is fixed by the connection's first message. Tokens sent with later ones are ignored,
+            // so a connection can't switch to another user's memories partway through:
+            let memory_scope: &MemoryScope = memory_scope.get_or_insert_with(|| {
+                // Without a usable token, memories are scoped to this session alone:
+                let user_id = match message.user_token() {
+                    Some(token) if token.chars().count() >= MIN_USER_TOKEN_LEN => content_hash(token),
+                    Some(_) => {
+                        warn!("Session {session_id} sent a user token too short to be secret; ignoring it");
+                        session_id.clone()
+                    }
+                    None => session_id.clone(),
+                };
+                info!("Session {session_id} belongs to user {user_id}");
+
+                MemoryScope::new(user_id, &session_id)
+            });
+
+            // Memory commands are handled here, and never reach the agent:
+            if let Some(command) = MemoryCommand::parse(user_input) {
+                let to_client = command
+                    .execute(
+                        self.memory_store.as_ref(),
+                        &self.memory_writer,
+                        memory_scope,
+                    )
+                    .await;
+                ui_channel.send(to_client).await;
+                continue;
+            }
+
+            let agent = agent.get_or_insert_with(|| {
+                let make_agent = make_agent.take().expect("The agent is only made once");
+                make_agent(memory_scope.clone())
+            });
+
             // You can get a full response:
             // let agent_response = agent.get_response(&user_input).await;
 
             // But we will stream the response piece by piece:
             info!("Requesting response from agent...");
-            let mut response = String::new();
-            let mut stream = agent
-                .get_response_stream(&user_input, &mut ui_channel)
-                .await;
-
-            // Yes, it is an Option<Option<T>>
-            // Outer layer is from the Stream, inner layer is our own data.
-            // A None from either indicates we should stop.
-            while let Some(Some(t)) = stream.next().await {
-                debug!("Received input from agent stream: {t}");
-                response.push_str(&t);
-            }
-            info!("Finished reading response from agent stream:\n{response}");
+            let mut stream = agent.get_response_stream(user_input);
+            let mut message_num = 0;
+
+            while let Some(event) = stream.next().await {
+                let to_client = match event {
+                    AgentEvent::Thought(thought) => {
+                        debug!("Agent thought: {thought}");
+                        continue;
+                    }
+                    AgentEvent::Action { action, input } => {
+                        debug!("Agent action: {action}({input})");
+                        continue;
+                    }
+                    AgentEvent::ConfirmationRequest {
+                        action,
+                        input,
+                        description,
+                        confirmation,
+                    } => {
+                        info!("Asking user to confirm {action}({input})");
+
+                        ui_channel
+                            .send(MessageToClient::new(
+                                String::from("ConfirmationRequest"),
+                                description,
+                                0,
+                            ))
+                            .await;
 
-            // Re-bind, because we no longer need it to be mut:
-            let response = response;
+                        // The turn is paused until the user answers. Anything else they say meanwhile waits its turn:
+                        let approved = loop {
+                            let reply: MessageFromClient =
+                                serde_json::from_str(&ui_channel.receive().await).unwrap();
 
-            ui_channel
-                .send(MessageToClient::new(String::new(), response, 0))
-                .await;
+                            if let Some(approved) = reply.confirmation_answer() {
+                                break approved;
+                            }
+
+                            info!("Holding back message until {action} is confirmed or denied");
+                            held_back.push_back(reply);
+                        };
+                        info!("User answered confirmation for {action}: approved={approved}");
+
+                        confirmation.respond(approved);
+                        continue;
+                    }
+                    AgentEvent::ToolProgress(text) => MessageToClient::new(String::new(), text, 0),
+                    AgentEvent::ToolOutput(text) => {
+                        MessageToClient::new(String::from("ToolInfo"), text, 0)
+                    }
+                    AgentEvent::Sources(sources) => {
+                        let json =
+                            serde_json::to_string(&sources).expect("Could not serialize sources");
+                        MessageToClient::new(String::from("Sources"), json, 0)
+                    }
+                    AgentEvent::ResponseDelta(delta) => {
+                        let message = MessageToClient::new(String::new(), delta, message_num);
+                        message_num += 1;
+                        message
+                    }
+                    AgentEvent::Done(result) => {
+                        info!(
+                            "Finished reading response from agent stream:\n{}",
+                            result.response
+                        );
+                        MessageToClient::new(String::from("Done"), result.response, message_num)
+                    }
+                };
+
+                ui_channel.send(to_client).await;
+            }
         }
     }
 }
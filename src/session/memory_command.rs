@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    memory::{MemoryScope, MemoryStore, MemoryWriter, METADATA_SOURCE, METADATA_TIMESTAMP},
+    model_client::{MemoryDeleteRequest, MemoryGetRequest, MemoryGetResponse, MemoryListRequest},
+    server::MessageToClient,
+};
+
+/// How many memories `/memories` shows.
+const LIST_LIMIT: usize = 20;
+
+/// The reply to commands that need to list or delete memories, when the store can't.
+const CANT_LIST: &str =
+    "Memories kept on the model server can only be searched, with /memories search <query>. \
+    Listing and forgetting them needs the local memory store, enabled with RAINCHAIN_MEMORY=local.";
+
+/// Slash commands that let users see and correct what has been remembered about them.
+/// Every command is limited to the memories of the session's own user.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum MemoryCommand {
+    /// `/memories`
+    List,
+
+    /// `/memories search <query>`
+    Search(String),
+
+    /// `/forget <id>`
+    Forget(String),
+
+    /// `/forget all`
+    ForgetAll,
+}
+
+/// A memory as rendered by the webui.
+#[derive(Serialize, Debug)]
+struct MemoryListItem {
+    id: String,
+    document: String,
+    timestamp: Option<u64>,
+    source: Option<String>,
+}
+
+impl MemoryCommand {
+    /// Parses a chat message as a memory command, if it is one.
+    pub(super) fn parse(message: &str) -> Option<Self> {
+        let message = message.trim();
+        let (command, argument) = message
+            .split_once(char::is_whitespace)
+            .map_or((message, ""), |(c, a)| (c, a.trim()));
+
+        match (command, argument) {
+            ("/memories", "") => Some(MemoryCommand::List),
+            ("/memories", argument) => {
+                let query = argument.strip_prefix("search")?.trim();
+                (!query.is_empty()).then(|| MemoryCommand::Search(query.to_owned()))
+            }
+            ("/forget", "all") => Some(MemoryCommand::ForgetAll),
+            ("/forget", id) if !id.is_empty() => Some(MemoryCommand::Forget(id.to_owned())),
+            _ => None,
+        }
+    }
+
+    pub(super) async fn execute(
+        self,
+        memory_store: &(dyn MemoryStore + Send + Sync),
+        memory_writer: &MemoryWriter,
+        memory_scope: &MemoryScope,
+    ) -> MessageToClient {
+        info!("Executing memory command: {self:?}");
+
+        // Without a way to list and delete memories, only searching them works:
+        if !matches!(self, MemoryCommand::Search(_)) && !memory_store.can_manage() {
+            return MessageToClient::new(String::new(), String::from(CANT_LIST), 0);
+        }
+
+        match self {
+            MemoryCommand::List => {
+                let request = MemoryListRequest {
+                    filter: memory_scope.user_filter(),
+                    limit: LIST_LIMIT,
+                    ..Default::default()
+                };
+                match memory_store.list(&request).await {
+                    Ok(response) => memory_list_message(response),
+                    Err(e) => MessageToClient::new(
+                        String::new(),
+                        format!("Failed to list memories: {e}"),
+                        0,
+                    ),
+                }
+            }
+            MemoryCommand::Search(query) => {
+                let request = MemoryGetRequest {
+                    query,
+                    filter: memory_scope.user_filter(),
+                };
+                let response = memory_store.query(&request).await;
+
+                memory_list_message(response)
+            }
+            MemoryCommand::Forget(id) => {
+                // The user filter makes sure nobody can forget, or even find, someone else's memory by guessing its id:
+                let lookup = MemoryListRequest {
+                    ids: vec![id.clone()],
+                    filter: memory_scope.user_filter(),
+                    limit: 1,
+                    ..Default::default()
+                };
+
+                let text = match memory_store.list(&lookup).await {
+                    Ok(found) if found.ids.is_empty() => {
+                        format!("There is no memory {id} to forget.")
+                    }
+                    Ok(_) => {
+                        let request = MemoryDeleteRequest {
+                            ids: vec![id.clone()],
+                            filter: memory_scope.user_filter(),
+                        };
+
+                        // Through the writer, so a memory still waiting to be written can't come back after being forgotten:
+                        match memory_writer.delete(request).await {
+                            Ok(()) => format!("Forgot memory {id}."),
+                            Err(e) => format!("Failed to forget memory {id}: {e}"),
+                        }
+                    }
+                    Err(e) => format!("Failed to look up memory {id}: {e}"),
+                };
+
+                MessageToClient::new(String::new(), text, 0)
+            }
+            MemoryCommand::ForgetAll => {
+                let request = MemoryDeleteRequest::by_filter(memory_scope.user_filter());
+                let text = match memory_writer.delete(request).await {
+                    Ok(()) => String::from("Forgot everything about you."),
+                    Err(e) => format!("Failed to forget your memories: {e}"),
+                };
+
+                MessageToClient::new(String::new(), text, 0)
+            }
+        }
+    }
+}
+
+fn memory_list_message(response: MemoryGetResponse) -> MessageToClient {
+    let items: Vec<MemoryListItem> = response
+        .ids
+        .into_iter()
+        .zip(response.documents)
+        .zip(response.metadatas)
+        .map(|((id, document), metadata)| {
+            let metadata: HashMap<String, String> =
+                serde_json::from_str(&metadata).unwrap_or_default();
+
+            MemoryListItem {
+                id,
+                document,
+                timestamp: metadata
+                    .get(METADATA_TIMESTAMP)
+                    .and_then(|t| t.parse().ok()),
+                source: metadata.get(METADATA_SOURCE).cloned(),
+            }
+        })
+        .collect();
+
+    let json = serde_json::to_string(&items).expect("Could not serialize memory list");
+
+    MessageToClient::new(String::from("MemoryList"), json, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_memory_commands() {
+        assert_eq!(MemoryCommand::parse("/memories"), Some(MemoryCommand::List));
+        assert_eq!(
+            MemoryCommand::parse(" /memories search  my cat "),
+            Some(MemoryCommand::Search(String::from("my cat")))
+        );
+        assert_eq!(
+            MemoryCommand::parse("/forget all"),
+            Some(MemoryCommand::ForgetAll)
+        );
+        assert_eq!(
+            MemoryCommand::parse("/forget 18c3-2"),
+            Some(MemoryCommand::Forget(String::from("18c3-2")))
+        );
+    }
+
+    #[test]
+    fn leaves_other_messages_alone() {
+        for message in [
+            "/memories foo",
+            "/memories search",
+            "/forget",
+            "what /memories do you have?",
+            "/memoriesearch x",
+        ] {
+            assert_eq!(MemoryCommand::parse(message), None, "{message}");
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
-use crate::model_client::ModelClient;
+use crate::{conversation::ChatMessage, model_client::ModelClient};
 
 pub mod home_automation;
 pub mod noop;
@@ -8,12 +9,72 @@ pub mod web_search;
 
 #[async_trait]
 pub trait Tool {
+    /// Runs the tool on `input`, as the model wrote it.
+    /// `recent_messages` is the end of the conversation so far, ending with the user's message,
+    /// for tools whose input may only make sense in context.
+    /// Tools with side effects ask `confirmer` before making any, once they know exactly what they are about to do.
+    /// The agent refuses every confirmation asked for by a tool that doesn't declare side effects.
     async fn get_output(
         &self,
         input: &str,
-        user_message: &str,
+        recent_messages: &[ChatMessage],
         model_client: &(dyn ModelClient + Send + Sync),
-    ) -> String;
+        confirmer: &(dyn Confirmer + Send + Sync),
+    ) -> ToolOutput;
 
-    fn name(&self) -> &str;
+    fn name(&self) -> &'static str;
+
+    /// Whether the tool may change anything outside the conversation, such as a device in the user's home.
+    /// Only tools that do may ask the user to confirm, and they must before doing so.
+    fn has_side_effects(&self) -> bool {
+        false
+    }
+
+    /// Shown to the user while the tool runs.
+    fn progress_message(&self, input: &str) -> String {
+        format!("{}: {input}", self.name())
+    }
+}
+
+/// Asks the user whether a tool may go ahead with an action.
+#[async_trait]
+pub trait Confirmer {
+    /// Waits for the user to approve or deny `description`, which says exactly what will be done.
+    async fn confirm(&self, description: &str) -> bool;
+}
+
+/// Where a piece of a tool's output came from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub title: String,
+    pub url: String,
+}
+
+/// What a tool found, for the model to answer from.
+/// Where the text cites a source, it does so as `[n]`: the `n`th of `sources`, counting from 1.
+#[derive(Debug, Clone, Default)]
+pub struct ToolOutput {
+    text: String,
+    sources: Vec<Source>,
+}
+
+impl ToolOutput {
+    pub fn new(text: impl Into<String>, sources: Vec<Source>) -> Self {
+        Self {
+            text: text.into(),
+            sources,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    pub fn into_parts(self) -> (String, Vec<Source>) {
+        (self.text, self.sources)
+    }
 }
@@ -1,21 +1,562 @@
-use super::Tool;
-use crate::model_client::ModelClient;
+use std::{collections::HashMap, error::Error, time::Duration};
+
 use async_trait::async_trait;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{Confirmer, Tool, ToolOutput};
+use crate::{
+    conversation::ChatMessage,
+    load_prompt_text,
+    model_client::{GuidanceRequestBuilder, ModelClient},
+};
+
+/// Only entities in these domains are offered to the model; the rest are too noisy to be useful.
+const CONTROLLABLE_DOMAINS: &[&str] = &[
+    "light",
+    "switch",
+    "fan",
+    "cover",
+    "lock",
+    "climate",
+    "media_player",
+    "scene",
+    "script",
+    "sensor",
+    "binary_sensor",
+];
+
+/// Reading the current state is handled here, and works for every entity.
+/// Every other service is called on Home Assistant.
+const SERVICE_GET_STATE: &str = "get_state";
+
+/// The services Home Assistant has for entities in `domain`, besides reading their state.
+fn domain_services(domain: &str) -> &'static [&'static str] {
+    match domain {
+        "light" | "switch" | "fan" | "media_player" => &["turn_on", "turn_off", "toggle"],
+        "climate" => &["turn_on", "turn_off"],
+        "cover" => &["open_cover", "close_cover", "stop_cover", "toggle"],
+        "lock" => &["lock", "unlock"],
+        "scene" | "script" => &["turn_on"],
+        // Sensors can only be read:
+        _ => &[],
+    }
+}
+
+/// Controls devices through the Home Assistant REST API.
+pub struct HomeAutomation {
+    base_url: String,
+    token: String,
+}
+
+impl HomeAutomation {
+    pub const NAME: &'static str = "HOME_AUTOMATION";
+
+    /// `base_url` is the root of the Home Assistant instance (e.g. `http://homeassistant.local:8123`),
+    /// and `token` a long-lived access token for it.
+    pub fn new(base_url: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+            token: token.into(),
+        }
+    }
+
+    async fn list_entities(&self) -> Result<Vec<EntityState>, Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/api/states", self.base_url);
+        debug!("Listing Home Assistant entities from {url}");
+
+        let states = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(&self.token)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<EntityState>>()
+            .await?;
+
+        Ok(states
+            .into_iter()
+            .filter(|state| CONTROLLABLE_DOMAINS.contains(&state.domain()))
+            .collect())
+    }
+
+    /// Calls `domain.service` on the entity, returning the states that changed as a result.
+    async fn call_service(
+        &self,
+        entity: &EntityState,
+        service: &str,
+    ) -> Result<Vec<EntityState>, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "{}/api/services/{}/{service}",
+            self.base_url,
+            entity.domain()
+        );
+        info!(
+            "Calling Home Assistant service {url} on {}",
+            entity.entity_id
+        );
+
+        let changed = reqwest::Client::new()
+            .post(url)
+            .bearer_auth(&self.token)
+            .json(&json!({ "entity_id": entity.entity_id }))
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<EntityState>>()
+            .await?;
+
+        Ok(changed)
+    }
+
+    /// Asks the model which entity, and which service on it, best match what the user asked for.
+    async fn choose_entity_and_service<'a>(
+        &self,
+        input: &str,
+        entities: &'a [EntityState],
+        model_client: &(dyn ModelClient + Send + Sync),
+    ) -> Option<(&'a EntityState, String)> {
+        let prompt = load_prompt_text("home_automation.txt");
+
+        let entity_ids: Vec<&str> = entities.iter().map(|e| e.entity_id.as_str()).collect();
+        let entity_objects: Vec<EntityDescription> =
+            entities.iter().map(EntityDescription::from).collect();
+
+        let mut services = Vec::<&str>::new();
+        for service in entities.iter().flat_map(EntityState::services) {
+            if !services.contains(&service) {
+                services.push(service);
+            }
+        }
+
+        let request = GuidanceRequestBuilder::new(prompt)
+            .with_parameter("user_input", input)
+            .with_object_parameter("entities", &entity_objects)
+            .with_parameter_list("entity_ids", &entity_ids)
+            .with_parameter_list("services", &services)
+            .build();
+
+        let response = model_client.request_guidance(&request).await;
+
+        let entity_id = response.expect_variable("entity_id").trim();
+        let service = response.expect_variable("service").trim();
+
+        info!("Mapped '{input}' to {service} on {entity_id}");
+
+        let entity = entities.iter().find(|e| e.entity_id == entity_id)?;
 
-pub struct HomeAutomation;
+        Some((entity, service.to_owned()))
+    }
+
+    async fn run(
+        &self,
+        input: &str,
+        model_client: &(dyn ModelClient + Send + Sync),
+        confirmer: &(dyn Confirmer + Send + Sync),
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let entities = self.list_entities().await?;
+
+        if entities.is_empty() {
+            return Ok(String::from(
+                "Home Assistant has no devices that can be controlled.",
+            ));
+        }
+
+        let Some((entity, service)) = self
+            .choose_entity_and_service(input, &entities, model_client)
+            .await
+        else {
+            return Ok(format!("No device matched '{input}'."));
+        };
+
+        self.perform(entity, &service, confirmer).await
+    }
+
+    /// Carries out `service` on `entity`, if it is something that can be done with it.
+    /// Anything but reading its state is only done once `confirmer` approves.
+    async fn perform(
+        &self,
+        entity: &EntityState,
+        service: &str,
+        confirmer: &(dyn Confirmer + Send + Sync),
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        if service == SERVICE_GET_STATE {
+            return Ok(entity.summary());
+        }
+
+        let services = entity.services();
+
+        if !services.contains(&service) {
+            return Ok(format!(
+                "{} can't {service}. It can only: {}.",
+                entity.name(),
+                services.join(", ")
+            ));
+        }
+
+        // What the model understood may not be what the user meant, so the user sees exactly what will be done:
+        let action = format!(
+            "{}.{service}({}) on {}",
+            entity.domain(),
+            entity.entity_id,
+            entity.name()
+        );
+
+        if !confirmer.confirm(&action).await {
+            info!("User denied {action}");
+            return Ok(format!(
+                "The user chose not to allow {action}, so nothing was done."
+            ));
+        }
+
+        info!("User approved {action}");
+
+        let changed = self.call_service(entity, service).await?;
+
+        // Home Assistant reports every state that changed; the one we targeted is what matters most.
+        let summary = changed
+            .iter()
+            .find(|e| e.entity_id == entity.entity_id)
+            .map_or_else(
+                || format!("Called {service} on {}.", entity.name()),
+                |e| format!("Called {service}. {}", e.summary()),
+            );
+
+        Ok(summary)
+    }
+}
 
 #[async_trait]
 impl Tool for HomeAutomation {
-    fn name(&self) -> &str {
-        "HOME_AUTOMATION"
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn has_side_effects(&self) -> bool {
+        true
+    }
+
+    fn progress_message(&self, input: &str) -> String {
+        format!("Controlling home: {input}")
     }
 
     async fn get_output(
         &self,
         input: &str,
-        _user_message: &str,
+        _recent_messages: &[ChatMessage],
         model_client: &(dyn ModelClient + Send + Sync),
-    ) -> String {
-        todo!("not done yet")
+        confirmer: &(dyn Confirmer + Send + Sync),
+    ) -> ToolOutput {
+        let text = match self.run(input, model_client, confirmer).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                warn!("Home Assistant request failed: {e}");
+                format!("Could not reach Home Assistant: {e}")
+            }
+        };
+
+        ToolOutput::new(text, Vec::new())
+    }
+}
+
+/// An entity as returned by Home Assistant's `/api/states`.
+#[derive(Deserialize, Debug, Clone)]
+struct EntityState {
+    entity_id: String,
+    state: String,
+    #[serde(default)]
+    attributes: HashMap<String, serde_json::Value>,
+}
+
+impl EntityState {
+    fn domain(&self) -> &str {
+        self.entity_id
+            .split_once('.')
+            .map_or(self.entity_id.as_str(), |(domain, _)| domain)
+    }
+
+    /// Everything that can be done with the entity, starting with reading its state.
+    fn services(&self) -> Vec<&'static str> {
+        std::iter::once(SERVICE_GET_STATE)
+            .chain(domain_services(self.domain()).iter().copied())
+            .collect()
+    }
+
+    fn name(&self) -> &str {
+        self.attributes
+            .get("friendly_name")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or(&self.entity_id)
+    }
+
+    /// A one-line description of the entity's state, e.g. "Kitchen Light is on."
+    fn summary(&self) -> String {
+        let unit = self
+            .attributes
+            .get("unit_of_measurement")
+            .and_then(serde_json::Value::as_str)
+            .map(|unit| format!(" {unit}"))
+            .unwrap_or_default();
+
+        format!("{} is {}{unit}.", self.name(), self.state)
+    }
+}
+
+/// What the model is shown about each entity.
+#[derive(Serialize)]
+struct EntityDescription {
+    entity_id: String,
+    name: String,
+    state: String,
+
+    /// The services that can be picked for the entity, e.g. `get_state, lock, unlock`.
+    services: String,
+}
+
+impl From<&EntityState> for EntityDescription {
+    fn from(entity: &EntityState) -> Self {
+        Self {
+            entity_id: entity.entity_id.clone(),
+            name: entity.name().to_owned(),
+            state: entity.state.clone(),
+            services: entity.services().join(", "),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Serves just enough of Home Assistant's REST API for the tool, and records every request it gets.
+    async fn stub_home_assistant() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+
+                // Reads until the headers, and the body they announce, are in:
+                loop {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_ascii_lowercase()
+                                    .strip_prefix("content-length:")
+                                    .map(|n| n.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or_default();
+
+                        if body.len() >= length || read == 0 {
+                            break;
+                        }
+                    }
+                }
+
+                let text = String::from_utf8_lossy(&request).into_owned();
+                let request_line = text.lines().next().unwrap_or_default().to_owned();
+                let authorized = text.contains("Bearer secret-token");
+                recorded.lock().unwrap().push(request_line.clone());
+
+                let body = match request_line.split_whitespace().nth(1) {
+                    _ if !authorized => None,
+                    Some("/api/states") => Some(json!([
+                        state("lock.front_door", "unlocked", "Front Door"),
+                        state("cover.garage", "closed", "Garage Door"),
+                        {
+                            "entity_id": "sensor.outside",
+                            "state": "12",
+                            "attributes": { "friendly_name": "Outside", "unit_of_measurement": "°C" }
+                        },
+                        state("automation.lights_at_dusk", "on", "Lights at Dusk"),
+                    ])),
+                    Some("/api/services/lock/lock") => {
+                        Some(json!([state("lock.front_door", "locked", "Front Door")]))
+                    }
+                    Some("/api/services/cover/open_cover") => {
+                        Some(json!([state("cover.garage", "opening", "Garage Door")]))
+                    }
+                    _ => None,
+                };
+
+                let response = match body {
+                    Some(body) => {
+                        let body = body.to_string();
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                            body.len()
+                        )
+                    }
+                    None => String::from(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    ),
+                };
+
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn state(entity_id: &str, state: &str, name: &str) -> serde_json::Value {
+        json!({ "entity_id": entity_id, "state": state, "attributes": { "friendly_name": name } })
+    }
+
+    /// Gives the same answer to every confirmation, and records what it was asked.
+    struct StubConfirmer {
+        approve: bool,
+        asked: Mutex<Vec<String>>,
+    }
+
+    impl StubConfirmer {
+        fn new(approve: bool) -> Self {
+            Self {
+                approve,
+                asked: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Confirmer for StubConfirmer {
+        async fn confirm(&self, description: &str) -> bool {
+            self.asked.lock().unwrap().push(description.to_owned());
+            self.approve
+        }
+    }
+
+    fn entity<'a>(entities: &'a [EntityState], entity_id: &str) -> &'a EntityState {
+        entities.iter().find(|e| e.entity_id == entity_id).unwrap()
+    }
+
+    #[tokio::test]
+    async fn calls_the_services_of_each_domain() {
+        let (url, requests) = stub_home_assistant().await;
+        let home = HomeAutomation::new(format!("{url}/"), "secret-token");
+
+        let confirmer = StubConfirmer::new(true);
+
+        let entities = home.list_entities().await.unwrap();
+        let ids: Vec<&str> = entities.iter().map(|e| e.entity_id.as_str()).collect();
+        assert_eq!(ids, ["lock.front_door", "cover.garage", "sensor.outside"]);
+
+        let locked = home
+            .perform(entity(&entities, "lock.front_door"), "lock", &confirmer)
+            .await
+            .unwrap();
+        assert_eq!(locked, "Called lock. Front Door is locked.");
+
+        let opened = home
+            .perform(entity(&entities, "cover.garage"), "open_cover", &confirmer)
+            .await
+            .unwrap();
+        assert_eq!(opened, "Called open_cover. Garage Door is opening.");
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                "GET /api/states HTTP/1.1",
+                "POST /api/services/lock/lock HTTP/1.1",
+                "POST /api/services/cover/open_cover HTTP/1.1",
+            ]
+        );
+
+        assert_eq!(
+            *confirmer.asked.lock().unwrap(),
+            [
+                "lock.lock(lock.front_door) on Front Door",
+                "cover.open_cover(cover.garage) on Garage Door",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_reads_sensors_and_refuses_services_a_domain_lacks() {
+        let (url, requests) = stub_home_assistant().await;
+        let home = HomeAutomation::new(url, "secret-token");
+
+        let confirmer = StubConfirmer::new(true);
+
+        let entities = home.list_entities().await.unwrap();
+        requests.lock().unwrap().clear();
+
+        let state = home
+            .perform(
+                entity(&entities, "sensor.outside"),
+                SERVICE_GET_STATE,
+                &confirmer,
+            )
+            .await
+            .unwrap();
+        assert_eq!(state, "Outside is 12 °C.");
+
+        let refused = home
+            .perform(entity(&entities, "sensor.outside"), "turn_on", &confirmer)
+            .await
+            .unwrap();
+        assert_eq!(refused, "Outside can't turn_on. It can only: get_state.");
+
+        let refused = home
+            .perform(entity(&entities, "lock.front_door"), "turn_off", &confirmer)
+            .await
+            .unwrap();
+        assert_eq!(
+            refused,
+            "Front Door can't turn_off. It can only: get_state, lock, unlock."
+        );
+
+        assert!(requests.lock().unwrap().is_empty());
+        assert!(confirmer.asked.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn does_nothing_the_user_denies() {
+        let (url, requests) = stub_home_assistant().await;
+        let home = HomeAutomation::new(url, "secret-token");
+        let confirmer = StubConfirmer::new(false);
+
+        let entities = home.list_entities().await.unwrap();
+        requests.lock().unwrap().clear();
+
+        let denied = home
+            .perform(entity(&entities, "lock.front_door"), "unlock", &confirmer)
+            .await
+            .unwrap();
+        assert_eq!(
+            denied,
+            "The user chose not to allow lock.unlock(lock.front_door) on Front Door, so nothing was done."
+        );
+
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_when_home_assistant_rejects_the_token() {
+        let (url, _) = stub_home_assistant().await;
+        let home = HomeAutomation::new(url, "wrong-token");
+
+        assert!(home.list_entities().await.is_err());
     }
 }
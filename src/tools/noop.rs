@@ -1,23 +1,28 @@
 use async_trait::async_trait;
 
-use crate::model_client::ModelClient;
+use crate::{conversation::ChatMessage, model_client::ModelClient};
 
-use super::Tool;
+use super::{Confirmer, Tool, ToolOutput};
 
 pub struct Noop;
 
+impl Noop {
+    pub const NAME: &'static str = "NONE";
+}
+
 #[async_trait]
 impl Tool for Noop {
     async fn get_output(
         &self,
         _input: &str,
-        _user_message: &str,
+        _recent_messages: &[ChatMessage],
         _model_client: &(dyn ModelClient + Send + Sync),
-    ) -> String {
-        String::new()
+        _confirmer: &(dyn Confirmer + Send + Sync),
+    ) -> ToolOutput {
+        ToolOutput::default()
     }
 
-    fn name(&self) -> &str {
-        "NONE"
+    fn name(&self) -> &'static str {
+        Self::NAME
     }
 }
@@ -1,252 +1,976 @@
-use std::{error::Error, time::Duration, vec};
+use std::{error::Error, fmt::Write, sync::Arc, time::Duration, vec};
 
 use async_trait::async_trait;
-use futures::future;
-use log::{debug, info, trace};
+use futures::{future, stream::FuturesUnordered, StreamExt};
+use log::{debug, info, trace, warn};
 use ordered_float::OrderedFloat;
-use serde::Deserialize;
-
+use reqwest::{header::CACHE_CONTROL, Url};
+use serde::{Deserialize, Serialize};
+use tokio::time::{timeout_at, Instant};
+
+use self::{
+    chunker::Chunk,
+    extract::{extract, EXTRACTABLE_CONTENT_TYPES},
+    page::Page,
+    ranking::Bm25,
+    robots::RobotsTxt,
+};
 use crate::{
+    cache::DiskCache,
+    config::CacheConfig,
+    conversation::{ChatMessage, Conversation},
     load_prompt_text,
     model_client::{Embedding, EmbeddingsRequest, GuidanceRequestBuilder, ModelClient},
 };
 
-use super::Tool;
-
-const MAX_SECTION_LEN: usize = 1000;
+use super::{Confirmer, Source, Tool, ToolOutput};
+
+mod brave;
+mod chunker;
+mod duckduckgo;
+mod extract;
+mod fetcher;
+mod google;
+mod page;
+mod politeness;
+mod ranking;
+mod reranker;
+mod robots;
+mod searxng;
+mod selection;
+
+pub use brave::BraveSearch;
+pub use chunker::{Chunker, EMBEDDING_MODEL_MAX_TOKENS};
+pub use duckduckgo::DuckDuckGoSearch;
+pub use fetcher::Fetcher;
+pub use google::GoogleSearch;
+pub use ranking::{FusionMethod, HybridRanker};
+pub use reranker::{RerankMethod, Reranker};
+pub use searxng::SearxngSearch;
+pub use selection::PassageSelector;
+
+/// Pages with less text than this are most likely error or consent pages.
+const MIN_PAGE_TEXT_LEN: usize = 50;
 const TOP_N_SECTIONS: usize = 3;
+const TOP_N_RESULTS: usize = 6;
+
+/// Once this many pages have been read, the rest aren't waited for.
+const ENOUGH_PAGES: usize = 4;
+
+/// The share of what is left of a search's time budget, once it is known what to search for,
+/// that searching and reading pages may use up. The rest is kept for ranking.
+const FETCH_SHARE_OF_BUDGET: f64 = 0.6;
+
+/// How long a search may take if not told otherwise, in milliseconds.
+/// Long enough to read a few pages and rank them, short enough that the user isn't left waiting.
+pub const DEFAULT_TIME_BUDGET_MS: u64 = 10_000;
+
+/// How long working out what to search for may take if not told otherwise, in milliseconds.
+/// Long enough for the model to write a short question and a query or two.
+pub const DEFAULT_REWRITE_TIMEOUT_MS: u64 = 5_000;
 
-pub struct WebSearch;
+/// What the model is told when there is nothing to answer from, so it doesn't answer from nothing.
+const NO_RESULTS: &str = "    No web results were found.";
+
+const CACHE_SEARCHES: &str = "searches";
+const CACHE_PAGES: &str = "pages";
+const CACHE_EMBEDDINGS: &str = "embeddings";
+const CACHE_ROBOTS: &str = "robots";
+
+/// How long a site's robots.txt is trusted for, as RFC 9309 recommends.
+const ROBOTS_TXT_TTL: Duration = Duration::from_hours(24);
+
+/// How long to wait before trying again to get a robots.txt that couldn't be fetched.
+/// Timeouts and server errors usually pass quickly, and every page on the site is refused until then.
+const ROBOTS_TXT_RETRY_TTL: Duration = Duration::from_mins(2);
+
+/// The longest `Crawl-delay` honoured. Sites asking for more are fetched from this often.
+const MAX_CRAWL_DELAY: Duration = Duration::from_secs(5);
+
+/// How much work `WebSearch` puts into each search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Reads the pages found, and picks the passages from them that best answer the question.
+    Full,
+
+    /// Answers from the search engine's own snippets, without fetching any pages, computing any embeddings,
+    /// or having the model rewrite the input, which is searched for as it is.
+    /// Much faster, but only as good as the snippets.
+    SnippetsOnly,
+}
 
+/// A web search engine, queried for the pages most likely to answer a query.
 #[async_trait]
-impl Tool for WebSearch {
-    fn name(&self) -> &str {
-        "WEB_SEARCH"
+pub trait SearchProvider {
+    /// Identifies the engine, so results from different engines are cached separately.
+    fn name(&self) -> &'static str;
+
+    /// The engine's results for `query`, best first.
+    async fn search(&self, query: &str) -> Result<Vec<SearchResult>, Box<dyn Error + Send + Sync>>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub link: String,
+    pub snippet: String,
+}
+
+impl SearchResult {
+    /// The result's snippet as a passage. `None` if it has no snippet.
+    /// It isn't headed by the title, as a chunk of the page would be, since the output already heads each passage with it.
+    fn snippet_passage(&self) -> Option<String> {
+        let snippet = self
+            .snippet
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if snippet.is_empty() {
+            return None;
+        }
+
+        Some(snippet)
     }
+}
 
-    async fn get_output(
+pub struct WebSearch {
+    provider: Arc<dyn SearchProvider + Send + Sync>,
+    fetcher: Fetcher,
+    cache: DiskCache,
+    cache_config: CacheConfig,
+    chunker: Chunker,
+    ranker: HybridRanker,
+    reranker: Option<Reranker>,
+    selector: PassageSelector,
+    mode: SearchMode,
+    time_budget: Duration,
+    rewrite_timeout: Duration,
+    max_sub_queries: usize,
+}
+
+/// What to search for, spelled out so it makes sense without the conversation it came up in.
+#[derive(Debug)]
+struct RewrittenQuery {
+    /// What passages are judged by how well they answer.
+    question: String,
+
+    /// The web searches to run, one for each part of the question, in order.
+    queries: Vec<String>,
+}
+
+impl RewrittenQuery {
+    /// Searches for `input` just as the model wrote it.
+    fn verbatim(input: &str) -> Self {
+        Self {
+            question: input.to_owned(),
+            queries: vec![input.to_owned()],
+        }
+    }
+}
+
+impl WebSearch {
+    pub const NAME: &'static str = "WEB_SEARCH";
+
+    pub fn new(
+        provider: Arc<dyn SearchProvider + Send + Sync>,
+        fetcher: Fetcher,
+        cache_config: CacheConfig,
+        chunker: Chunker,
+        ranker: HybridRanker,
+        selector: PassageSelector,
+    ) -> Self {
+        Self {
+            provider,
+            fetcher,
+            cache: DiskCache::new(&cache_config.dir),
+            cache_config,
+            chunker,
+            ranker,
+            reranker: None,
+            selector,
+            mode: SearchMode::Full,
+            time_budget: Duration::from_millis(DEFAULT_TIME_BUDGET_MS),
+            rewrite_timeout: Duration::from_millis(DEFAULT_REWRITE_TIMEOUT_MS),
+            max_sub_queries: 1,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: SearchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Finishes each search within `time_budget`, giving up on slow pages and skipping slow steps if need be.
+    pub fn with_time_budget(mut self, time_budget: Duration) -> Self {
+        self.time_budget = time_budget;
+        self
+    }
+
+    /// Searches for the input as the model wrote it, if it can't be rewritten as a standalone question within `rewrite_timeout`.
+    /// Counts towards the time budget.
+    pub fn with_rewrite_timeout(mut self, rewrite_timeout: Duration) -> Self {
+        self.rewrite_timeout = rewrite_timeout;
+        self
+    }
+
+    /// Splits questions about several separate things into up to `max_sub_queries` searches, one for each.
+    /// By default, every question gets a single search.
+    pub fn with_max_sub_queries(mut self, max_sub_queries: usize) -> Self {
+        self.max_sub_queries = max_sub_queries.max(1);
+        self
+    }
+
+    /// Has `reranker` take a second look at the best-ranked passages before the top few are picked.
+    pub fn with_reranker(mut self, reranker: Reranker) -> Self {
+        self.reranker = Some(reranker);
+        self
+    }
+
+    /// The best few results of searching for each of `queries` at once, taking turns so each gets its share,
+    /// and leaving out pages already found by another. Searches not done by `deadline` find nothing.
+    async fn search_all(&self, queries: &[String], deadline: Instant) -> Vec<SearchResult> {
+        let result_lists = future::join_all(
+            queries
+                .iter()
+                .map(|query| self.top_results(query, deadline)),
+        )
+        .await;
+
+        let longest = result_lists.iter().map(Vec::len).max().unwrap_or_default();
+        let mut merged = Vec::<SearchResult>::new();
+
+        for rank in 0..longest {
+            for item in result_lists.iter().filter_map(|results| results.get(rank)) {
+                let link = normalize_url(&item.link);
+
+                if !merged.iter().any(|seen| normalize_url(&seen.link) == link) {
+                    merged.push(item.clone());
+                }
+            }
+        }
+
+        merged.truncate(TOP_N_RESULTS);
+        merged
+    }
+
+    /// The results of searching for all of `queries`, each with its page if it could be read,
+    /// leaving enough time before `deadline` to rank what was read.
+    async fn search_and_read(
+        &self,
+        queries: &[String],
+        deadline: Instant,
+    ) -> Vec<(SearchResult, Option<Page>)> {
+        let fetch_deadline = fetch_deadline(deadline);
+        let results = self.search_all(queries, fetch_deadline).await;
+        let pages = self.fetch_pages(&results, fetch_deadline).await;
+
+        results.into_iter().zip(pages).collect()
+    }
+
+    /// The best few results of searching for `query`, or none if the search isn't done by `deadline`.
+    async fn top_results(&self, query: &str, deadline: Instant) -> Vec<SearchResult> {
+        if let Ok(results) = timeout_at(deadline, self.search(query)).await {
+            results.into_iter().take(TOP_N_RESULTS).collect()
+        } else {
+            warn!("Out of time searching for '{query}'");
+            Vec::new()
+        }
+    }
+
+    async fn search(&self, query: &str) -> Vec<SearchResult> {
+        let query = query.replace('"', "");
+
+        // Queries differing only in case or spacing get the same results:
+        let cache_key = format!(
+            "{}:{}",
+            self.provider.name(),
+            query
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase()
+        );
+
+        if let Some(results) = self.cache.get(CACHE_SEARCHES, &cache_key).await {
+            return results;
+        }
+
+        match self.provider.search(&query).await {
+            Ok(results) => {
+                debug!("Got {} results", results.len());
+
+                self.cache
+                    .put(
+                        CACHE_SEARCHES,
+                        &cache_key,
+                        &results,
+                        self.cache_config.search_ttl,
+                    )
+                    .await;
+
+                results
+            }
+            Err(e) => {
+                warn!("Search for '{query}' failed: {e}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// The readable content of the page at `url`, from the cache if it is there.
+    /// Pages are cached for as long as their `Cache-Control` header allows, up to the configured TTL.
+    async fn fetch_page(&self, url: &str) -> Result<Page, Box<dyn Error + Send + Sync>> {
+        let cache_key = normalize_url(url);
+
+        if let Some(page) = self.cache.get(CACHE_PAGES, &cache_key).await {
+            return Ok(page);
+        }
+
+        if !self.robots_txt_allows(url).await {
+            return Err(format!("Not fetching {url}: its robots.txt disallows it").into());
+        }
+
+        let (page, max_age) = scrape(&self.fetcher, url).await?;
+
+        let ttl = max_age.map_or(self.cache_config.page_ttl, |max_age| {
+            max_age.min(self.cache_config.page_ttl)
+        });
+
+        self.cache.put(CACHE_PAGES, &cache_key, &page, ttl).await;
+
+        Ok(page)
+    }
+
+    /// Fetches the pages of `results` all at once, in the order given, with `None` for any that couldn't be read in time.
+    /// Stops waiting once `ENOUGH_PAGES` have been read or `deadline` passes, cancelling any fetches still going.
+    async fn fetch_pages(&self, results: &[SearchResult], deadline: Instant) -> Vec<Option<Page>> {
+        let mut pages = vec![None; results.len()];
+        let mut read = 0;
+
+        let mut fetches: FuturesUnordered<_> = results
+            .iter()
+            .enumerate()
+            .map(|(index, item)| async move { (index, self.fetch_page(&item.link).await) })
+            .collect();
+
+        let out_of_time = tokio::time::sleep_until(deadline);
+        tokio::pin!(out_of_time);
+
+        while read < ENOUGH_PAGES {
+            tokio::select! {
+                next = fetches.next() => match next {
+                    Some((index, Ok(page))) => {
+                        if page.text_len() > MIN_PAGE_TEXT_LEN {
+                            read += 1;
+                        }
+
+                        pages[index] = Some(page);
+                    }
+                    Some((index, Err(e))) => debug!("Could not use {}: {e}", results[index].link),
+                    None => break,
+                },
+                () = &mut out_of_time => {
+                    debug!("Out of time to read pages, giving up on {} of them", fetches.len());
+                    break;
+                }
+            }
+        }
+
+        pages
+    }
+
+    /// Works out what the model's `input` means given the `recent_messages` of the conversation,
+    /// and what to search for to answer it.
+    async fn rewrite(
         &self,
         input: &str,
-        _user_message: &str,
+        recent_messages: &[ChatMessage],
         model_client: &(dyn ModelClient + Send + Sync),
-    ) -> String {
-        // Search the web and find relevant text, split into sections:
-        let sections: Vec<String> = {
-            let top_links = search(input).await;
+    ) -> RewrittenQuery {
+        debug!("Rewriting input '{input}' as a standalone question");
+        let rewrite_prompt = load_prompt_text("guider_rewrite_query.txt");
+        let request = GuidanceRequestBuilder::new(rewrite_prompt)
+            .with_parameter("history", Conversation::messages_to_string(recent_messages))
+            .with_parameter("user_input", input)
+            .with_parameter("max_queries", self.max_sub_queries.to_string())
+            .build();
+        let response = model_client.request_guidance(&request).await;
+
+        let question = response
+            .variable("question")
+            .map(str::trim)
+            .filter(|question| !question.is_empty())
+            .unwrap_or(input)
+            .to_owned();
+
+        let mut queries = Vec::<String>::new();
+
+        for line in response.variable("queries").unwrap_or_default().lines() {
+            let query = line
+                .trim()
+                .trim_start_matches('-')
+                .trim()
+                .trim_matches(['"', '\''])
+                .trim();
+
+            if !query.is_empty() && !queries.iter().any(|q| q.eq_ignore_ascii_case(query)) {
+                queries.push(query.to_owned());
+            }
+        }
 
-            let scrape_futures = top_links.into_iter().take(6).map(scrape);
+        queries.truncate(self.max_sub_queries);
 
-            future::join_all(scrape_futures)
-                .await
-                .into_iter()
-                .filter_map(Result::ok)
-                .filter(|text| text.len() > 50)
-                .flat_map(|text| split_text_into_sections(text, MAX_SECTION_LEN))
-                .collect()
+        if queries.is_empty() {
+            warn!("Got no search queries for '{input}', searching for it as it is");
+            queries.push(input.to_owned());
+        }
+
+        info!("Rewrote input '{input}' as question '{question}', searching for {queries:?}");
+
+        RewrittenQuery { question, queries }
+    }
+
+    /// Whether the robots.txt of the site `url` is on lets us fetch it, from the cache if it is there.
+    /// Also slows down requests to the site, if its robots.txt asks.
+    async fn robots_txt_allows(&self, url: &str) -> bool {
+        // Anything unparseable won't be fetched anyway:
+        let Ok(url) = Url::parse(url) else {
+            return true;
         };
 
-        // Get embeddings for the sections:
-        let corpus_embeddings = {
-            debug!("Getting embeddings for {} text extracts...", sections.len());
-            let sections_as_query = sections
-                .iter()
-                .map(|text| format!("passage: {text}"))
-                .collect();
+        let origin = url.origin().ascii_serialization();
 
-            let embeddings_result = model_client
-                .request_embeddings(&EmbeddingsRequest::new(sections_as_query))
+        let robots_txt = if let Some(robots_txt) = self.cache.get(CACHE_ROBOTS, &origin).await {
+            robots_txt
+        } else {
+            let (robots_txt, ttl) = match self.fetcher.fetch_robots_txt(&url).await {
+                Ok(response) => (
+                    RobotsTxt::parse(&String::from_utf8_lossy(&response.body)),
+                    ROBOTS_TXT_TTL,
+                ),
+                Err(e) => {
+                    let status = e
+                        .downcast_ref::<reqwest::Error>()
+                        .and_then(reqwest::Error::status);
+
+                    debug!("No robots.txt for {origin}: {e}");
+
+                    // As RFC 9309 says: no robots.txt allows everything,
+                    // but one that can't be reached, for whatever reason, allows nothing for now.
+                    match status {
+                        Some(status) if status.is_client_error() => {
+                            (RobotsTxt::default(), ROBOTS_TXT_TTL)
+                        }
+                        _ => (RobotsTxt::disallow_all(), ROBOTS_TXT_RETRY_TTL),
+                    }
+                }
+            };
+
+            self.cache
+                .put(CACHE_ROBOTS, &origin, &robots_txt, ttl)
                 .await;
 
-            let mut corpus_embeddings = embeddings_result.take_embeddings();
-            corpus_embeddings.sort_unstable_by_key(Embedding::index);
+            robots_txt
+        };
+
+        let product = self.fetcher.product_token();
+
+        if let (Some(delay), Some(host)) = (robots_txt.crawl_delay(product), url.host_str()) {
+            // Waiting longer than a search can take would just stall every search that reaches the host:
+            self.fetcher
+                .set_crawl_delay(host, delay.min(MAX_CRAWL_DELAY));
+        }
 
-            {
-                let len = corpus_embeddings.len();
-                debug!("Got {len} embeddings.");
+        let path = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_owned(),
+        };
+
+        robots_txt.is_allowed(product, &path)
+    }
+
+    /// Re-orders the best `reranker.candidates()` of `ranked` (pairs of section index and score, best first) by the re-ranker's scores.
+    /// The rest keep their place after them. If re-ranking fails, `ranked` is returned unchanged.
+    async fn rerank(
+        &self,
+        reranker: Reranker,
+        question: &str,
+        sections: &[String],
+        mut ranked: Vec<(usize, OrderedFloat<f32>)>,
+        model_client: &(dyn ModelClient + Send + Sync),
+    ) -> Vec<(usize, OrderedFloat<f32>)> {
+        let candidate_count = reranker.candidates().min(ranked.len());
+        let passages: Vec<&str> = ranked[..candidate_count]
+            .iter()
+            .map(|(index, _)| sections[*index].as_str())
+            .collect();
+
+        let Some(scores) = reranker.score(question, &passages, model_client).await else {
+            return ranked;
+        };
+
+        for ((index, score), rerank_score) in ranked.iter_mut().zip(scores) {
+            trace!("Section {index}: first-stage {score}, re-ranked {rerank_score}");
+            *score = OrderedFloat(rerank_score);
+        }
+
+        // Stable, so candidates the re-ranker couldn't tell apart keep their first-stage order:
+        ranked[..candidate_count].sort_by_key(|(_, score)| -*score);
+
+        ranked
+    }
+
+    /// Embeds each of `sections` as a passage, in order, with the embedding `model`.
+    /// Sections embedded before by the same model are taken from the cache, keyed by the model and their text,
+    /// and only the rest are sent to the model.
+    /// `None` if the model didn't send back an embedding for every section it was sent.
+    async fn embed_sections(
+        &self,
+        sections: &[String],
+        model: &str,
+        model_client: &(dyn ModelClient + Send + Sync),
+    ) -> Option<Vec<Vec<f32>>> {
+        let passages: Vec<String> = sections
+            .iter()
+            .map(|text| format!("passage: {text}"))
+            .collect();
+
+        let cached: Vec<Option<Vec<f32>>> = future::join_all(passages.iter().map(|passage| {
+            let key = embedding_cache_key(model, passage);
+            async move { self.cache.get(CACHE_EMBEDDINGS, &key).await }
+        }))
+        .await;
+
+        let missing: Vec<String> = passages
+            .iter()
+            .zip(&cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(passage, _)| passage.clone())
+            .collect();
+
+        debug!(
+            "Getting embeddings for {} text extracts ({} cached)...",
+            passages.len(),
+            passages.len() - missing.len()
+        );
+
+        let (fetched_model, fetched) = if missing.is_empty() {
+            (String::new(), Vec::new())
+        } else {
+            let response = model_client
+                .request_embeddings(&EmbeddingsRequest::new(missing.clone()))
+                .await;
+            let fetched_model = response.model.clone();
+
+            let mut embeddings = response.take_embeddings();
+            embeddings.sort_unstable_by_key(Embedding::index);
+
+            debug!("Got {} embeddings.", embeddings.len());
+
+            if embeddings.len() != missing.len() {
+                warn!(
+                    "Asked for {} embeddings, but got {}",
+                    missing.len(),
+                    embeddings.len()
+                );
+                return None;
             }
 
-            corpus_embeddings
+            (fetched_model, embeddings)
         };
 
-        // Transform user input into a question:
-        let user_embed_str: String = {
-            debug!("Turning input '{input}' into a question");
-            let question_prompt = load_prompt_text("guider_generate_question.txt");
-            let request = GuidanceRequestBuilder::new(question_prompt)
-                .with_parameter("user_input", input)
-                .build();
-            let response = model_client.request_guidance(&request).await;
+        let mut fetched = fetched
+            .into_iter()
+            .zip(missing)
+            .map(|(embedding, passage)| (passage, embedding.embedding().to_vec()));
+
+        let mut embeddings = Vec::with_capacity(passages.len());
+
+        for cached in cached {
+            if let Some(embedding) = cached {
+                embeddings.push(embedding);
+                continue;
+            }
+
+            let (passage, embedding) = fetched.next()?;
+
+            // Filed under whichever model actually made it, in case the backend's model has changed since:
+            self.cache
+                .put(
+                    CACHE_EMBEDDINGS,
+                    &embedding_cache_key(&fetched_model, &passage),
+                    &embedding,
+                    self.cache_config.embedding_ttl,
+                )
+                .await;
+
+            embeddings.push(embedding);
+        }
+
+        Some(embeddings)
+    }
+}
+
+#[async_trait]
+impl Tool for WebSearch {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn progress_message(&self, input: &str) -> String {
+        format!("Searching: {input}")
+    }
+
+    async fn get_output(
+        &self,
+        input: &str,
+        recent_messages: &[ChatMessage],
+        model_client: &(dyn ModelClient + Send + Sync),
+        _confirmer: &(dyn Confirmer + Send + Sync),
+    ) -> ToolOutput {
+        let deadline = Instant::now() + self.time_budget;
+        let rewrite_deadline = Instant::now() + self.rewrite_timeout.min(self.time_budget);
 
-            let response_str = response
-                .variable("response")
-                .expect("Expected guidance to populate the 'response' variable");
+        if self.mode == SearchMode::SnippetsOnly {
+            // Rewriting would take longer than the rest of the search, so the input is searched for as it is:
+            let top_results = self.search_all(&[input.to_owned()], deadline).await;
 
-            info!("Converted input '{}' to question '{}'", input, response_str);
+            // The search engine's order is the only ranking there is:
+            let passages = top_results
+                .iter()
+                .enumerate()
+                .filter_map(|(source, item)| Some((source, item.snippet_passage()?)));
+
+            return cited_output(&top_results, passages);
+        }
 
-            // response_str.into()
-            format!("query: {response_str}")
+        // The input is searched for, and its pages read, while it is rewritten,
+        // so no time is lost if it turns out to need no rewriting, or can't be rewritten in time:
+        let input_query = [input.to_owned()];
+        let mut verbatim = Box::pin(self.search_and_read(&input_query, deadline));
+        let mut verbatim_found = None;
+
+        // Follow-ups like "what's the third one about?" can't be searched for until they're spelled out:
+        let rewrite = timeout_at(
+            rewrite_deadline,
+            self.rewrite(input, recent_messages, model_client),
+        );
+        tokio::pin!(rewrite);
+
+        let rewritten = loop {
+            tokio::select! {
+                rewritten = &mut rewrite => break rewritten,
+                found = &mut verbatim, if verbatim_found.is_none() => verbatim_found = Some(found),
+            }
+        };
+
+        let query = rewritten.unwrap_or_else(|_| {
+            info!(
+                "Could not rewrite '{input}' within {:?}, searching for it as it is",
+                self.rewrite_timeout
+            );
+            RewrittenQuery::verbatim(input)
+        });
+
+        let is_verbatim = query
+            .queries
+            .iter()
+            .all(|query| query.trim().eq_ignore_ascii_case(input.trim()));
+
+        let found = if is_verbatim {
+            match verbatim_found {
+                Some(found) => found,
+                None => verbatim.await,
+            }
+        } else {
+            // In context, the input means something other than what it says, so what it finds as written is beside the point.
+            // Its unfinished page reads are dropped, so they don't hold up the reads of pages that matter:
+            drop(verbatim);
+            self.search_and_read(&query.queries, deadline).await
         };
 
-        // Get embedding for user query:
-        let user_input_embedding = {
+        // Search the web and find relevant text, split into sections.
+        // Each section remembers the index of the search result it came from.
+        let (top_results, pages): (Vec<_>, Vec<_>) = found.into_iter().unzip();
+
+        let mut section_sources = Vec::new();
+        let mut sections = Vec::new();
+
+        for (source, (item, page)) in top_results.iter().zip(pages).enumerate() {
+            let passages: Vec<String> = match page {
+                Some(page) if page.text_len() > MIN_PAGE_TEXT_LEN => self
+                    .chunker
+                    .chunk(&page)
+                    .iter()
+                    .map(Chunk::to_passage)
+                    .collect(),
+                _ => Vec::new(),
+            };
+
+            // A page that couldn't be read still has what the search engine said about it:
+            let passages = if passages.is_empty() {
+                debug!("Falling back to the search snippet for {}", item.link);
+                item.snippet_passage().into_iter().collect()
+            } else {
+                passages
+            };
+
+            section_sources.extend(std::iter::repeat_n(source, passages.len()));
+            sections.extend(passages);
+        }
+
+        if sections.is_empty() {
+            return ToolOutput::new(NO_RESULTS, Vec::new());
+        }
+
+        // Exact matches count for the terms the user actually typed, which rewriting may have reworded,
+        // as well as for what was searched for:
+        let users_words = recent_messages
+            .iter()
+            .rev()
+            .find(|message| message.is_user())
+            .map_or(input, ChatMessage::text);
+        let lexical =
+            Bm25::new(&sections).scores(&format!("{users_words} {}", query.queries.join(" ")));
+        let question = query.question;
+
+        // Get embeddings for the sections and the question, and score each section by meaning too,
+        // unless that would take longer than there is left, in which case wording alone has to do:
+        let user_embed_str = format!("query: {question}");
+
+        let semantic_scores = timeout_at(deadline, async {
+            // The question is embedded first, to learn which model the cached sections must have been embedded by:
             let response = model_client
                 .request_embeddings(&EmbeddingsRequest::new(vec![user_embed_str.clone()]))
                 .await;
-            let embeddings = response.take_embeddings();
-            embeddings.into_iter().next().expect("Expected embeddings")
+            let model = response.model.clone();
+            let Some(user_input_embedding) = response.take_embeddings().into_iter().next() else {
+                warn!("Got no embedding for the question");
+                return None;
+            };
+
+            let corpus_embeddings = self.embed_sections(&sections, &model, model_client).await?;
+
+            debug!("Finding closest matches for: {user_embed_str}");
+            let semantic: Vec<f32> = corpus_embeddings
+                .iter()
+                .map(|e| cosine_similarity(user_input_embedding.embedding(), e))
+                .collect();
+
+            Some((corpus_embeddings, semantic))
+        })
+        .await;
+
+        let (corpus_embeddings, fused) = match semantic_scores {
+            Ok(Some((corpus_embeddings, semantic))) => {
+                for (index, (semantic, lexical)) in semantic.iter().zip(&lexical).enumerate() {
+                    trace!("Section {index}: semantic {semantic}, lexical {lexical}");
+                }
+
+                let fused = self.ranker.fuse(&semantic, &lexical);
+                (corpus_embeddings, fused)
+            }
+            missing => {
+                if missing.is_err() {
+                    warn!("Out of time for embeddings, ranking passages by their wording alone");
+                } else {
+                    warn!("Missing embeddings, ranking passages by their wording alone");
+                }
+
+                // Without embeddings, passages can only be told apart by their words:
+                (vec![Vec::new(); sections.len()], lexical)
+            }
         };
 
-        // Get scores for each embedding, and sort from best to worst:
+        // Sort from best to worst:
         let with_scores = {
-            debug!("Finding closest matches for: {user_embed_str}");
-            let mut with_scores: Vec<_> = corpus_embeddings
+            let mut with_scores: Vec<_> = fused
                 .into_iter()
-                .map(|e| {
-                    let similarity =
-                        cosine_similarity(user_input_embedding.embedding(), e.embedding());
-                    (e, OrderedFloat(similarity))
-                })
+                .enumerate()
+                .map(|(index, score)| (index, OrderedFloat(score)))
                 .collect();
 
-            with_scores.sort_unstable_by_key(|(_, score)| -*score);
+            with_scores.sort_by_key(|(_, score)| -*score);
+
+            for (index, score) in &with_scores {
+                trace!("Section {index}: {score}");
+            }
 
             with_scores
         };
 
-        // Build final result from top-scoring embeddings:
-        {
-            let mut result = String::new();
-            for (n, (embedding, score)) in
-                with_scores.into_iter().take(TOP_N_SECTIONS + 3).enumerate()
-            {
-                let index = embedding.index();
-                let original_text = &sections[index];
-                debug!("Score {score}: {original_text}");
-
-                if n < TOP_N_SECTIONS {
-                    result.push_str(&format!("    [WEB_RESULT {n}]: {original_text}\n"));
-                }
-            }
-
-            // Trailing newline
-            result.pop();
+        // Optionally, take a closer look at the best candidates to settle the final order:
+        let with_scores = match self.reranker {
+            Some(reranker) => timeout_at(
+                deadline,
+                self.rerank(
+                    reranker,
+                    &question,
+                    &sections,
+                    with_scores.clone(),
+                    model_client,
+                ),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                warn!("Out of time to re-rank passages, keeping their first-stage order");
+                with_scores
+            }),
+            None => with_scores,
+        };
 
-            result
-        }
+        // Pick the best passages that each add something the others don't:
+        let ranked: Vec<usize> = with_scores.into_iter().map(|(index, _)| index).collect();
+        let selected = self.selector.select(
+            &ranked,
+            &sections,
+            &section_sources,
+            &corpus_embeddings,
+            TOP_N_SECTIONS,
+        );
+
+        cited_output(
+            &top_results,
+            selected
+                .into_iter()
+                .map(|index| (section_sources[index], sections[index].clone())),
+        )
     }
 }
 
-fn split_text_into_sections(input: impl Into<String>, max_section_len: usize) -> Vec<String> {
-    let mut result = Vec::<String>::new();
+/// Embeddings from different models can't be compared, so each is cached under the model that made it.
+fn embedding_cache_key(model: &str, passage: &str) -> String {
+    format!("{model}\n{passage}")
+}
 
-    let input: String = input.into();
+/// When searching and reading pages must be done by, starting now, for the search to be done by `deadline`.
+fn fetch_deadline(deadline: Instant) -> Instant {
+    let now = Instant::now();
+    now + deadline
+        .saturating_duration_since(now)
+        .mul_f64(FETCH_SHARE_OF_BUDGET)
+}
 
-    for sentence in input
-        .split_terminator(&['.', '\n'])
-        .filter(|s| !s.is_empty())
-    {
-        let sentence = sentence.trim().to_owned();
-        if let Some(last) = result.last_mut() {
-            if last.len() + sentence.len() > max_section_len {
-                result.push(sentence);
-            } else {
-                last.push_str(". ");
-                last.push_str(&sentence);
-            }
+/// Lists `passages` (pairs of the search result each came from, and its text) for the model, in order,
+/// each labeled with the number of the source the model cites it by.
+/// Passages from the same result share a number, which is that result's place in the output's sources, counting from 1.
+fn cited_output(
+    top_results: &[SearchResult],
+    passages: impl IntoIterator<Item = (usize, String)>,
+) -> ToolOutput {
+    let mut result = String::new();
+    let mut sources = Vec::<Source>::new();
+
+    for (source, text) in passages {
+        debug!("Passage from result {source}: {text}");
+
+        let item = &top_results[source];
+        let number = if let Some(position) = sources.iter().position(|s| s.url == item.link) {
+            position + 1
         } else {
-            result.push(sentence);
-        }
+            sources.push(Source {
+                title: item.title.clone(),
+                url: item.link.clone(),
+            });
+            sources.len()
+        };
+
+        let _ = writeln!(
+            result,
+            "    [{number}] {} ({}):\n    {}",
+            item.title,
+            item.link,
+            text.replace('\n', "\n    ")
+        );
+    }
+
+    if result.is_empty() {
+        return ToolOutput::new(NO_RESULTS, Vec::new());
     }
 
-    result
+    // Trailing newline
+    result.pop();
+
+    ToolOutput::new(result, sources)
 }
 
-fn cosine_similarity(vec1: &[f32], vec2: &[f32]) -> f32 {
+pub(crate) fn cosine_similarity(vec1: &[f32], vec2: &[f32]) -> f32 {
     let dot_product: f32 = vec1.iter().zip(vec2.iter()).map(|(a, b)| a * b).sum();
     let magnitude_vec1: f32 = vec1.iter().map(|&n| n.powi(2)).sum::<f32>().sqrt();
     let magnitude_vec2: f32 = vec2.iter().map(|&n| n.powi(2)).sum::<f32>().sqrt();
 
-    dot_product / (magnitude_vec1 * magnitude_vec2)
-}
-
-async fn search(query: &str) -> Vec<String> {
-    let query = query.replace('"', "");
+    let magnitudes = magnitude_vec1 * magnitude_vec2;
 
-    debug!("Searching Google for '{query}'");
-
-    let (api_key, cx) = get_api_key_cx();
-    let client = reqwest::Client::new();
-
-    let response = client
-        .get("https://www.googleapis.com/customsearch/v1")
-        .query(&[
-            ("key", api_key.as_str()),
-            ("cx", cx.as_str()),
-            ("q", &query),
-        ])
-        .timeout(Duration::from_millis(5000))
-        .send()
-        .await
-        .unwrap()
-        .json::<Response>()
-        .await
-        .unwrap();
-
-    let len = response.items.len();
-    debug!("Got {len} results");
+    // An empty or zero vector is like nothing:
+    if magnitudes == 0.0 {
+        return 0.0;
+    }
 
-    response.items.into_iter().map(|i| i.link).collect()
+    dot_product / magnitudes
 }
 
-async fn scrape(url: impl AsRef<str>) -> Result<String, Box<dyn Error + Send + Sync>> {
-    let url = url.as_ref();
-
+/// Fetches `url` with `fetcher` and extracts its readable content, whether it is a web page or a document.
+/// Also returns how long the page may be cached for, if its `Cache-Control` header says.
+async fn scrape(
+    fetcher: &Fetcher,
+    url: &str,
+) -> Result<(Page, Option<Duration>), Box<dyn Error + Send + Sync>> {
     debug!("Scraping: {url}...");
 
-    let client = reqwest::ClientBuilder::new()
-        .timeout(Duration::from_millis(2000))
-        .build()?;
+    let response = fetcher.fetch(url, EXTRACTABLE_CONTENT_TYPES).await?;
 
-    let response = client.get(url)
-        .header("User-Agent", "Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; compatible; Googlebot/2.1; +http://www.google.com/bot.html) Chrome/W.X.Y.Z Safari/537.36").send().await?;
-    let s = response.text().await?;
+    let max_age = response
+        .headers
+        .get(CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(cache_control_max_age);
 
-    info!("Read text from {} length: {}", url, s.len());
+    info!(
+        "Read {} from {} length: {}",
+        response.content_type,
+        response.url,
+        response.body.len()
+    );
 
-    let mut readability = readable_readability::Readability::new();
-    let (node_ref, _metadata) = readability
-        .strip_unlikelys(true)
-        .clean_attributes(true)
-        .parse(&s);
+    let page = extract(response).await?;
 
-    debug!("Done.");
+    info!("Scraped down to len: {}", page.text_len());
 
-    let text_content = node_ref.text_contents();
+    trace!("Scraped page:\n{page:#?}");
 
-    info!("Scraped down to len: {}", text_content.len());
+    Ok((page, max_age))
+}
 
-    trace!("Scraped text:\n{text_content}");
+/// How long a response may be cached for, according to its `Cache-Control` header value.
+/// Zero if it must not be cached, and `None` if the header doesn't say.
+fn cache_control_max_age(cache_control: &str) -> Option<Duration> {
+    let directives = cache_control
+        .split(',')
+        .map(|directive| directive.trim().to_ascii_lowercase());
 
-    Ok(text_content.trim().into())
-}
+    let mut max_age = None;
 
-fn get_api_key_cx() -> (String, String) {
-    let api_key =
-        std::fs::read_to_string("src/.googlekey.txt").expect("Expected to find google key file.");
-    let cx = std::fs::read_to_string("src/.googlecx.txt")
-        .expect("Expected to find google context file.");
+    for directive in directives {
+        if directive == "no-store" || directive == "no-cache" {
+            return Some(Duration::ZERO);
+        }
 
-    (api_key, cx)
-}
+        if let Some(secs) = directive.strip_prefix("max-age=") {
+            max_age = secs.trim_matches('"').parse().ok().map(Duration::from_secs);
+        }
+    }
 
-#[derive(Deserialize)]
-struct Response {
-    items: Vec<Item>,
+    max_age
 }
 
-#[derive(Deserialize)]
-struct Item {
-    title: String,
-    link: String,
-    snippet: String,
+/// The same page can be linked with different fragments, or with different casing in the host.
+fn normalize_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut url) => {
+            url.set_fragment(None);
+            url.into()
+        }
+        Err(_) => url.to_owned(),
+    }
 }
@@ -0,0 +1,75 @@
+use std::{error::Error, time::Duration};
+
+use async_trait::async_trait;
+use log::debug;
+use serde::Deserialize;
+
+use super::{SearchProvider, SearchResult};
+
+/// Searches with the Brave Search API.
+pub struct BraveSearch {
+    api_key: String,
+}
+
+impl BraveSearch {
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl SearchProvider for BraveSearch {
+    fn name(&self) -> &'static str {
+        "brave"
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<SearchResult>, Box<dyn Error + Send + Sync>> {
+        debug!("Searching Brave for '{query}'");
+
+        let response = reqwest::Client::new()
+            .get("https://api.search.brave.com/res/v1/web/search")
+            .query(&[("q", query)])
+            .header("Accept", "application/json")
+            .header("X-Subscription-Token", &self.api_key)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Response>()
+            .await?;
+
+        Ok(response
+            .web
+            .map(|web| web.results)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|result| SearchResult {
+                title: result.title,
+                link: result.url,
+                snippet: result.description,
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    // Missing when the query matched no web pages:
+    web: Option<WebResults>,
+}
+
+#[derive(Deserialize)]
+struct WebResults {
+    #[serde(default)]
+    results: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    title: String,
+    url: String,
+    #[serde(default)]
+    description: String,
+}
@@ -0,0 +1,414 @@
+use super::page::{Block, Page};
+
+/// The most tokens the embedding model reads from one input. Anything past this is ignored by the model.
+pub const EMBEDDING_MODEL_MAX_TOKENS: usize = 512;
+
+/// The most of each chunk's tokens its context may take up. Longer contexts are cut short,
+/// so pages with long titles or deeply nested headings still leave room for text.
+const MAX_CONTEXT_SHARE: usize = 2;
+
+/// Words ending in a period that rarely end a sentence.
+const ABBREVIATIONS: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "vs", "etc", "e.g", "i.e", "inc", "ltd",
+    "co", "corp", "no", "vol", "fig", "approx", "dept", "est", "gen", "gov", "mt", "jan", "feb",
+    "mar", "apr", "jun", "jul", "aug", "sep", "sept", "oct", "nov", "dec", "u.s", "u.k",
+];
+
+/// A piece of a page small enough to embed, with the headings it falls under.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// The page title and section headings leading to this chunk, e.g. "Rust > Memory safety".
+    pub context: String,
+    pub text: String,
+}
+
+impl Chunk {
+    /// The chunk as it is embedded and shown to the model: its text, preceded by its context.
+    pub fn to_passage(&self) -> String {
+        if self.context.is_empty() {
+            self.text.clone()
+        } else {
+            format!("{}\n{}", self.context, self.text)
+        }
+    }
+}
+
+/// Splits pages into chunks of whole sentences, each small enough for the embedding model,
+/// without ever letting a chunk span two sections of the page.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunker {
+    max_tokens: usize,
+    overlap_tokens: usize,
+}
+
+impl Chunker {
+    /// `max_tokens` bounds each chunk's passage, context included.
+    /// Each chunk starts with up to `overlap_tokens` worth of sentences from the end of the chunk before it,
+    /// so a sentence is never without the sentences leading up to it.
+    pub fn new(max_tokens: usize, overlap_tokens: usize) -> Self {
+        Self {
+            max_tokens: max_tokens.min(EMBEDDING_MODEL_MAX_TOKENS),
+            overlap_tokens: overlap_tokens.min(max_tokens / 2),
+        }
+    }
+
+    pub fn chunk(&self, page: &Page) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+
+        // Headings currently in effect, by level:
+        let mut headings: Vec<(u8, &str)> = Vec::new();
+        let mut section_sentences: Vec<&str> = Vec::new();
+
+        for block in &page.blocks {
+            match block {
+                Block::Heading(level, text) => {
+                    let context = build_context(page.title.as_deref(), &headings);
+                    self.chunk_section(&context, &section_sentences, &mut chunks);
+                    section_sentences.clear();
+
+                    headings.retain(|(existing, _)| existing < level);
+                    headings.push((*level, text));
+                }
+                Block::Paragraph(text) => section_sentences.extend(split_sentences(text)),
+            }
+        }
+
+        let context = build_context(page.title.as_deref(), &headings);
+        self.chunk_section(&context, &section_sentences, &mut chunks);
+
+        chunks
+    }
+
+    fn chunk_section(&self, context: &str, sentences: &[&str], chunks: &mut Vec<Chunk>) {
+        if sentences.is_empty() {
+            return;
+        }
+
+        let context = truncate_to_budget(context, self.max_tokens / MAX_CONTEXT_SHARE);
+        let context = context.as_str();
+
+        // A newline joins the context to the text:
+        let budget = self
+            .max_tokens
+            .saturating_sub(estimate_tokens(context) + 1)
+            .max(1);
+
+        // Sentences too long to fit in any chunk are broken up by words:
+        let pieces: Vec<String> = sentences
+            .iter()
+            .flat_map(|sentence| split_to_budget(sentence, budget))
+            .collect();
+
+        let mut current: Vec<&str> = Vec::new();
+        let mut current_tokens = 0;
+        // How many of `current`'s leading pieces were carried over from the chunk before:
+        let mut carried = 0;
+
+        for piece in &pieces {
+            let tokens = estimate_tokens(piece);
+
+            if current_tokens + tokens > budget && current.len() > carried {
+                chunks.push(Chunk {
+                    context: context.to_owned(),
+                    text: current.join(" "),
+                });
+
+                let overlap = self.overlap(&current, budget.saturating_sub(tokens));
+                carried = overlap.len();
+                current_tokens = overlap.iter().map(|piece| estimate_tokens(piece)).sum();
+                current = overlap;
+            }
+
+            current.push(piece.as_str());
+            current_tokens += tokens;
+        }
+
+        if current.len() > carried {
+            chunks.push(Chunk {
+                context: context.to_owned(),
+                text: current.join(" "),
+            });
+        }
+    }
+
+    /// The trailing pieces of `chunk` to repeat at the start of the next one,
+    /// no more than the overlap allows, nor more than `room` leaves space for.
+    fn overlap<'a>(&self, chunk: &[&'a str], room: usize) -> Vec<&'a str> {
+        let limit = self.overlap_tokens.min(room);
+        let mut tokens = 0;
+        let mut start = chunk.len();
+
+        while start > 0 {
+            let piece_tokens = estimate_tokens(chunk[start - 1]);
+            if tokens + piece_tokens > limit {
+                break;
+            }
+
+            tokens += piece_tokens;
+            start -= 1;
+        }
+
+        chunk[start..].to_vec()
+    }
+}
+
+fn build_context(title: Option<&str>, headings: &[(u8, &str)]) -> String {
+    title
+        .into_iter()
+        .chain(headings.iter().map(|(_, heading)| *heading))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" > ")
+}
+
+/// A rough count of the tokens the embedding model will see in `text`.
+/// Subword tokenizers average around four characters of English per token, and never merge across words.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.split_whitespace()
+        .map(|word| word.chars().count().div_ceil(4))
+        .sum()
+}
+
+/// Splits `text` into sentences. A sentence ends at `.`, `!` or `?` followed by whitespace and then
+/// something that could start a sentence, unless the period ends an abbreviation or an initial.
+/// Periods inside numbers and URLs are never followed by whitespace, so they never end a sentence.
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+
+    for (i, &(position, c)) in chars.iter().enumerate() {
+        if !matches!(c, '.' | '!' | '?') {
+            continue;
+        }
+
+        // Closing quotes and brackets belong to the sentence they close:
+        let mut end = i + 1;
+        while end < chars.len() && matches!(chars[end].1, '"' | '\'' | ')' | ']' | '”' | '’') {
+            end += 1;
+        }
+
+        let followed_by_space = chars.get(end).is_some_and(|(_, c)| c.is_whitespace());
+        if !followed_by_space {
+            continue;
+        }
+
+        let next_starts_sentence = chars[end..]
+            .iter()
+            .find(|(_, c)| !c.is_whitespace())
+            .is_some_and(|(_, c)| {
+                c.is_uppercase() || c.is_numeric() || matches!(c, '"' | '\'' | '(' | '[' | '“')
+            });
+        if !next_starts_sentence {
+            continue;
+        }
+
+        if c == '.' && ends_with_abbreviation(&text[start..position]) {
+            continue;
+        }
+
+        let end_position = chars.get(end).map_or(text.len(), |(position, _)| *position);
+        let sentence = text[start..end_position].trim();
+        if !sentence.is_empty() {
+            sentences.push(sentence);
+        }
+
+        start = end_position;
+    }
+
+    let rest = text[start..].trim();
+    if !rest.is_empty() {
+        sentences.push(rest);
+    }
+
+    sentences
+}
+
+/// True if the last word of `text`, which is followed by a period, is an abbreviation or a single-letter initial.
+fn ends_with_abbreviation(text: &str) -> bool {
+    let Some(word) = text.split_whitespace().last() else {
+        return false;
+    };
+
+    let word = word.trim_start_matches(['(', '"', '\'', '[']);
+
+    if word.chars().count() == 1 && word.chars().all(char::is_alphabetic) {
+        return true;
+    }
+
+    let word = word.to_lowercase();
+    ABBREVIATIONS.contains(&word.as_str())
+}
+
+/// Breaks `sentence` into pieces of whole words that each fit in `budget` tokens.
+/// Words too long to fit on their own, such as long URLs or encoded data, are broken up by characters.
+fn split_to_budget(sentence: &str, budget: usize) -> Vec<String> {
+    if estimate_tokens(sentence) <= budget {
+        return vec![sentence.to_owned()];
+    }
+
+    let mut pieces = Vec::new();
+    let mut current = Vec::new();
+    let mut current_tokens = 0;
+
+    let words = sentence
+        .split_whitespace()
+        .flat_map(|word| split_word_to_budget(word, budget));
+
+    for word in words {
+        let tokens = estimate_tokens(word);
+
+        if current_tokens + tokens > budget && !current.is_empty() {
+            pieces.push(current.join(" "));
+            current.clear();
+            current_tokens = 0;
+        }
+
+        current.push(word);
+        current_tokens += tokens;
+    }
+
+    if !current.is_empty() {
+        pieces.push(current.join(" "));
+    }
+
+    pieces
+}
+
+/// The leading words of `text` that fit in `budget` tokens, breaking up a first word too long to fit.
+fn truncate_to_budget(text: &str, budget: usize) -> String {
+    if estimate_tokens(text) <= budget {
+        return text.to_owned();
+    }
+
+    let mut words = Vec::new();
+    let mut tokens = 0;
+
+    for word in text
+        .split_whitespace()
+        .flat_map(|word| split_word_to_budget(word, budget))
+    {
+        tokens += estimate_tokens(word);
+        if tokens > budget {
+            break;
+        }
+
+        words.push(word);
+    }
+
+    words.join(" ")
+}
+
+/// Breaks `word` into runs of characters that each fit in `budget` tokens.
+fn split_word_to_budget(word: &str, budget: usize) -> Vec<&str> {
+    let max_chars = budget.max(1) * 4;
+    let mut pieces = Vec::new();
+    let mut rest = word;
+
+    while rest.chars().count() > max_chars {
+        let (split_at, _) = rest
+            .char_indices()
+            .nth(max_chars)
+            .expect("Expected more characters than the limit");
+
+        pieces.push(&rest[..split_at]);
+        rest = &rest[split_at..];
+    }
+
+    pieces.push(rest);
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_words_longer_than_the_budget() {
+        let blob = "A".repeat(4000);
+        let page = Page {
+            title: None,
+            blocks: vec![Block::Paragraph(format!(
+                "Some text before it. {blob} And some after."
+            ))],
+        };
+
+        let chunker = Chunker::new(256, 32);
+        let chunks = chunker.chunk(&page);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(estimate_tokens(&chunk.to_passage()) <= 256);
+        }
+
+        let text: String = chunks
+            .iter()
+            .map(|chunk| chunk.text.replace(' ', ""))
+            .collect();
+        assert!(text.matches('A').count() >= blob.len());
+    }
+
+    #[test]
+    fn keeps_sentences_together_across_abbreviations_decimals_and_urls() {
+        assert_eq!(
+            split_sentences("Dr. Smith met J. R. Tolkien, e.g. at home. It was late."),
+            vec!["Dr. Smith met J. R. Tolkien, e.g. at home.", "It was late."]
+        );
+        assert_eq!(
+            split_sentences("It costs 3.50 dollars. That is cheap!"),
+            vec!["It costs 3.50 dollars.", "That is cheap!"]
+        );
+        assert_eq!(
+            split_sentences("See https://example.com/a.b?c=d.e for more. Then stop."),
+            vec!["See https://example.com/a.b?c=d.e for more.", "Then stop."]
+        );
+        assert_eq!(
+            split_sentences("He said \"go.\" Then he left. and so on."),
+            vec!["He said \"go.\"", "Then he left. and so on."]
+        );
+    }
+
+    #[test]
+    fn repeats_the_end_of_each_chunk_at_the_start_of_the_next() {
+        let sentences: Vec<String> = (0..20)
+            .map(|i| format!("Sentence number {i} has some words."))
+            .collect();
+        let page = Page {
+            title: None,
+            blocks: vec![Block::Paragraph(sentences.join(" "))],
+        };
+
+        let chunker = Chunker::new(40, 10);
+        let chunks = chunker.chunk(&page);
+
+        assert!(chunks.len() > 1);
+        for pair in chunks.windows(2) {
+            let last_sentence = split_sentences(&pair[0].text).pop().unwrap();
+            assert!(pair[1].text.starts_with(last_sentence));
+            assert!(estimate_tokens(&pair[1].to_passage()) <= 40);
+        }
+
+        assert!(chunks[0].text.starts_with(&sentences[0]));
+        assert!(chunks.last().unwrap().text.ends_with(&sentences[19]));
+    }
+
+    #[test]
+    fn cuts_contexts_too_long_to_leave_room_for_text() {
+        let page = Page {
+            title: Some("A very long page title that goes on and on ".repeat(10)),
+            blocks: vec![
+                Block::Heading(1, "An equally long heading for the section ".repeat(10)),
+                Block::Paragraph(String::from("The only sentence on the page.")),
+            ],
+        };
+
+        let chunker = Chunker::new(32, 8);
+        let chunks = chunker.chunk(&page);
+
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].context.starts_with("A very long page title"));
+        assert_eq!(chunks[0].text, "The only sentence on the page.");
+        assert!(estimate_tokens(&chunks[0].to_passage()) <= 32);
+    }
+}
@@ -0,0 +1,88 @@
+use std::{error::Error, time::Duration};
+
+use async_trait::async_trait;
+use kuchiki::traits::TendrilSink;
+use log::{debug, warn};
+use reqwest::Url;
+
+use super::{SearchProvider, SearchResult};
+
+/// Searches by scraping the HTML-only results page of Duck Duck Go. Needs no API key,
+/// but may break whenever that page's markup changes.
+pub struct DuckDuckGoSearch;
+
+#[async_trait]
+impl SearchProvider for DuckDuckGoSearch {
+    fn name(&self) -> &'static str {
+        "duckduckgo"
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<SearchResult>, Box<dyn Error + Send + Sync>> {
+        debug!("Searching DuckDuckGo for '{query}'");
+
+        let html = reqwest::Client::new()
+            .post("https://html.duckduckgo.com/html/")
+            .form(&[("q", query)])
+            .header("User-Agent", "Mozilla/5.0 (compatible; rainchain)")
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(parse_results(&html))
+    }
+}
+
+fn parse_results(html: &str) -> Vec<SearchResult> {
+    let document = kuchiki::parse_html().one(html);
+
+    let Ok(results) = document.select(".result") else {
+        return Vec::new();
+    };
+
+    results
+        .filter_map(|result| {
+            let result = result.as_node();
+
+            let anchor = result.select_first(".result__a").ok()?;
+            let href = anchor.attributes.borrow().get("href")?.to_owned();
+
+            let Some(link) = resolve_link(&href) else {
+                warn!("Could not read DuckDuckGo result link: {href}");
+                return None;
+            };
+
+            let snippet = result
+                .select_first(".result__snippet")
+                .map(|snippet| snippet.text_contents())
+                .unwrap_or_default();
+
+            Some(SearchResult {
+                title: anchor.text_contents().trim().to_owned(),
+                link,
+                snippet: snippet.trim().to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Result links usually go through a redirector (`//duckduckgo.com/l/?uddg=<target>`),
+/// so the real target is taken from its query string.
+fn resolve_link(href: &str) -> Option<String> {
+    let url = if href.starts_with("//") {
+        Url::parse(&format!("https:{href}")).ok()?
+    } else {
+        Url::parse(href).ok()?
+    };
+
+    if url.path() == "/l/" {
+        return url
+            .query_pairs()
+            .find(|(key, _)| key == "uddg")
+            .map(|(_, target)| target.into_owned());
+    }
+
+    Some(url.into())
+}
@@ -0,0 +1,383 @@
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use encoding_rs::{Encoding, UTF_8};
+use log::{debug, warn};
+
+use super::{
+    fetcher::Fetched,
+    page::{Block, Page},
+};
+
+/// Every kind of content a page can be extracted from.
+pub const EXTRACTABLE_CONTENT_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "application/pdf",
+    "text/plain",
+    "text/markdown",
+    "text/x-markdown",
+    "application/json",
+];
+
+/// How far into an HTML page a `<meta>` charset declaration is looked for, as browsers do.
+const META_CHARSET_SNIFF_LEN: usize = 1024;
+
+/// A PDF is read no further than this many pages, or this much text, so a huge or
+/// maliciously compressed file can't fill memory. Whatever was read by then is used.
+const MAX_PDF_PAGES: usize = 200;
+const MAX_PDF_TEXT_LEN: usize = 512 * 1024;
+
+/// How long reading a PDF may take before it is given up on.
+const PDF_EXTRACT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The readable content of a fetched page, however it is formatted.
+pub async fn extract(response: Fetched) -> Result<Page, Box<dyn Error + Send + Sync>> {
+    let charset = response.charset.as_deref();
+
+    let page = match response.content_type.as_str() {
+        "application/pdf" => pdf_page(response.body).await?,
+        "text/plain" => Page::from_text(&decode(&response.body, charset), None),
+        "text/markdown" | "text/x-markdown" => {
+            Page::from_markdown(&decode(&response.body, charset), None)
+        }
+        "application/json" => json_page(&decode(&response.body, charset))?,
+        _ => html_page(&decode_html(&response.body, charset)),
+    };
+
+    Ok(page)
+}
+
+fn html_page(html: &str) -> Page {
+    let mut readability = readable_readability::Readability::new();
+    let (node_ref, metadata) = readability
+        .strip_unlikelys(true)
+        .clean_attributes(true)
+        .parse(html);
+
+    Page::from_article(&node_ref, metadata.article_title.or(metadata.page_title))
+}
+
+async fn pdf_page(body: Vec<u8>) -> Result<Page, Box<dyn Error + Send + Sync>> {
+    // A blocking thread can't be cancelled, so it is told to stop reading once this returns,
+    // whether it finished, timed out, or the search it was for ran out of time:
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let _abandon = SetOnDrop(abandoned.clone());
+
+    // Extraction is slow, and panics on some malformed files, so it gets a thread of its own:
+    let extraction = tokio::task::spawn_blocking(move || pdf_text(&body, &abandoned));
+    let text = tokio::time::timeout(PDF_EXTRACT_TIMEOUT, extraction)
+        .await
+        .map_err(|_| "PDF extraction took too long")?
+        .map_err(|e| format!("PDF extraction failed: {e}"))??;
+
+    Ok(Page::from_text(&join_hyphenated_lines(&text), None))
+}
+
+/// The text of a PDF, a page at a time, up to the limits above or until `abandoned` is set.
+fn pdf_text(body: &[u8], abandoned: &AtomicBool) -> Result<String, pdf_extract::OutputError> {
+    let mut document = pdf_extract::Document::load_mem(body)?;
+
+    // Most encrypted PDFs only restrict printing or copying, and open with an empty password:
+    if document.is_encrypted() {
+        document.decrypt("")?;
+    }
+
+    let mut text = String::new();
+
+    for page_number in document.get_pages().into_keys().take(MAX_PDF_PAGES) {
+        if abandoned.load(Ordering::Relaxed) || text.len() >= MAX_PDF_TEXT_LEN {
+            break;
+        }
+
+        let mut output = pdf_extract::PlainTextOutput::new(&mut text);
+        pdf_extract::output_doc_page(&document, &mut output, page_number)?;
+    }
+
+    text.truncate(text.floor_char_boundary(MAX_PDF_TEXT_LEN));
+
+    Ok(text)
+}
+
+/// Sets the flag when dropped.
+struct SetOnDrop(Arc<AtomicBool>);
+
+impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Pretty-printed, so the model sees its structure. It is kept whole, as there are no sentences to split it by.
+fn json_page(text: &str) -> Result<Page, Box<dyn Error + Send + Sync>> {
+    let value: serde_json::Value = serde_json::from_str(text)?;
+
+    Ok(Page {
+        title: None,
+        blocks: vec![Block::Paragraph(serde_json::to_string_pretty(&value)?)],
+    })
+}
+
+/// Decodes `body` from the encoding named by `charset`, or UTF-8 if it names none we know.
+/// A byte order mark overrides both.
+fn decode(body: &[u8], charset: Option<&str>) -> String {
+    let encoding = charset
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(UTF_8);
+
+    let (text, actual, had_errors) = encoding.decode(body);
+
+    if had_errors {
+        warn!(
+            "Some of the text was not valid {}, and was replaced",
+            actual.name()
+        );
+    }
+
+    text.into_owned()
+}
+
+/// Like [`decode`], but if the response didn't give a charset, the page may declare one in a `<meta>` tag.
+fn decode_html(body: &[u8], charset: Option<&str>) -> String {
+    let declared = charset.map(str::to_owned).or_else(|| {
+        let head = &body[..body.len().min(META_CHARSET_SNIFF_LEN)];
+        let label = meta_charset(head)?;
+        debug!("Page declares charset '{label}'");
+
+        // A page that could be read well enough to find this can't really be UTF-16, whatever it says:
+        Encoding::for_label(label.as_bytes())
+            .map(|encoding| encoding.output_encoding().name().to_owned())
+    });
+
+    decode(body, declared.as_deref())
+}
+
+/// The charset declared by a `<meta charset>` or `<meta http-equiv="Content-Type">` tag in `head`.
+fn meta_charset(head: &[u8]) -> Option<String> {
+    // Every charset a page can declare itself in is ASCII-compatible, as far as tags go:
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+
+    head.split("<meta").skip(1).find_map(|tag| {
+        let tag = tag.split('>').next().unwrap_or_default();
+        let (_, rest) = tag.split_once("charset")?;
+        let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+
+        let label: String = rest
+            .trim_start_matches(['"', '\''])
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+            .collect();
+
+        (!label.is_empty()).then_some(label)
+    })
+}
+
+/// Rejoins the words that a PDF's layout broke across lines with a hyphen.
+fn join_hyphenated_lines(text: &str) -> String {
+    let mut joined = String::with_capacity(text.len());
+    let mut lines = text.lines().peekable();
+    let mut continues_word = false;
+
+    while let Some(line) = lines.next() {
+        let line = if continues_word {
+            line.trim_start()
+        } else {
+            line
+        };
+
+        let next_starts_lowercase = lines
+            .peek()
+            .and_then(|next| next.trim_start().chars().next())
+            .is_some_and(char::is_lowercase);
+
+        continues_word = next_starts_lowercase
+            && line
+                .strip_suffix('-')
+                .and_then(|start| start.chars().last())
+                .is_some_and(char::is_alphabetic);
+
+        if continues_word {
+            joined.push_str(&line[..line.len() - 1]);
+        } else {
+            joined.push_str(line);
+            joined.push('\n');
+        }
+    }
+
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use pdf_extract::{
+        content::{Content, Operation},
+        dictionary, Document, Object, Stream,
+    };
+
+    use super::*;
+
+    /// A PDF with a page for each of `pages`, each holding its text on a single line.
+    fn pdf(pages: &[String]) -> Vec<u8> {
+        let mut document = Document::with_version("1.5");
+        let pages_id = document.new_object_id();
+
+        let font_id = document.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = document.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+
+        let page_ids: Vec<Object> = pages
+            .iter()
+            .map(|text| {
+                let content = Content {
+                    operations: vec![
+                        Operation::new("BT", vec![]),
+                        Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                        Operation::new("Td", vec![50.into(), 700.into()]),
+                        Operation::new("Tj", vec![Object::string_literal(text.as_str())]),
+                        Operation::new("ET", vec![]),
+                    ],
+                };
+                let content_id =
+                    document.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+
+                document
+                    .add_object(dictionary! {
+                        "Type" => "Page",
+                        "Parent" => pages_id,
+                        "Contents" => content_id,
+                    })
+                    .into()
+            })
+            .collect();
+
+        let page_count = i64::try_from(page_ids.len()).unwrap();
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => page_ids,
+                "Count" => page_count,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+
+        let mut body = Vec::new();
+        document.save_to(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn decodes_the_charset_the_response_gives() {
+        assert_eq!(decode(b"caf\xe9", Some("windows-1252")), "café");
+        assert_eq!(decode("café".as_bytes(), None), "café");
+        assert_eq!(decode("café".as_bytes(), Some("no-such-charset")), "café");
+    }
+
+    #[test]
+    fn lets_a_byte_order_mark_override_the_charset() {
+        assert_eq!(
+            decode(b"\xef\xbb\xbfcaf\xc3\xa9", Some("windows-1252")),
+            "café"
+        );
+        assert_eq!(decode(b"\xff\xfeh\0i\0", None), "hi");
+    }
+
+    #[test]
+    fn sniffs_the_charset_a_page_declares() {
+        let meta = b"<html><head><meta charset=\"ISO-8859-1\"></head><p>caf\xe9</p>";
+        assert!(decode_html(meta, None).contains("café"));
+
+        let http_equiv = b"<meta http-equiv='Content-Type' content='text/html; charset=windows-1252'><p>caf\xe9</p>";
+        assert!(decode_html(http_equiv, None).contains("café"));
+
+        // The response's charset wins over the page's:
+        let utf8 = "<meta charset=\"iso-8859-1\"><p>café</p>";
+        assert!(decode_html(utf8.as_bytes(), Some("utf-8")).contains("café"));
+
+        // A page readable as ASCII isn't UTF-16, whatever it says:
+        let utf16 = "<meta charset=\"utf-16\"><p>café</p>";
+        assert!(decode_html(utf16.as_bytes(), None).contains("café"));
+
+        // Declarations past the first kilobyte are too late to count:
+        let mut late = format!("<p>{}</p>", " ".repeat(META_CHARSET_SNIFF_LEN)).into_bytes();
+        late.extend_from_slice(b"<meta charset=\"windows-1252\"><p>caf\xe9</p>");
+        assert!(decode_html(&late, None).contains("caf\u{fffd}"));
+    }
+
+    #[test]
+    fn pretty_prints_json() {
+        let page = json_page(r#"{"name":"rain","tags":["a","b"]}"#).unwrap();
+
+        let [Block::Paragraph(text)] = page.blocks.as_slice() else {
+            panic!("Expected one paragraph, got {:?}", page.blocks);
+        };
+        assert_eq!(
+            text,
+            "{\n  \"name\": \"rain\",\n  \"tags\": [\n    \"a\",\n    \"b\"\n  ]\n}"
+        );
+
+        assert!(json_page("{not json").is_err());
+    }
+
+    #[test]
+    fn reads_no_more_than_the_page_limit() {
+        let pages: Vec<String> = (1..=MAX_PDF_PAGES + 1)
+            .map(|i| format!("Page{i}End"))
+            .collect();
+
+        let text = pdf_text(&pdf(&pages), &AtomicBool::new(false)).unwrap();
+
+        assert!(text.contains("Page1End"));
+        assert!(text.contains(&format!("Page{MAX_PDF_PAGES}End")));
+        assert!(!text.contains(&format!("Page{}End", MAX_PDF_PAGES + 1)));
+    }
+
+    #[test]
+    fn reads_no_more_than_the_text_limit() {
+        let page_len = MAX_PDF_TEXT_LEN / 5;
+        let pages: Vec<String> = (1..=8)
+            .map(|i| format!("Page{i}End {}", "x".repeat(page_len)))
+            .collect();
+
+        let text = pdf_text(&pdf(&pages), &AtomicBool::new(false)).unwrap();
+
+        assert!(text.len() <= MAX_PDF_TEXT_LEN);
+        assert!(text.contains("Page1End"));
+        assert!(!text.contains("Page8End"));
+    }
+
+    #[test]
+    fn stops_reading_once_abandoned() {
+        let pages = vec![String::from("Page1End")];
+
+        let text = pdf_text(&pdf(&pages), &AtomicBool::new(true)).unwrap();
+
+        assert!(text.is_empty());
+    }
+
+    #[test]
+    fn rejoins_hyphenated_words() {
+        assert_eq!(
+            join_hyphenated_lines("an exam-\nple of a 2-\nway Rust-\nLang"),
+            "an example of a 2-\nway Rust-\nLang\n"
+        );
+    }
+}
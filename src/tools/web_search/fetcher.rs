@@ -0,0 +1,433 @@
+use std::{
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, trace};
+use reqwest::{
+    header::{HeaderMap, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, USER_AGENT},
+    redirect, Url,
+};
+use tokio::sync::Semaphore;
+
+use super::politeness::Politeness;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Redirects followed before giving up on a page.
+const MAX_REDIRECTS: usize = 5;
+
+/// Sites that answer every path with their index page serve that for robots.txt too. It has no rules, so allows everything.
+const ROBOTS_TXT_CONTENT_TYPES: &[&str] = &["text/plain", "text/html"];
+
+/// A successful response to a fetch, after any redirects.
+#[derive(Debug)]
+pub struct Fetched {
+    /// Where the content was finally found.
+    pub url: Url,
+
+    /// The media type, lowercased and without parameters, e.g. `text/html`.
+    pub content_type: String,
+
+    /// The character encoding the `Content-Type` header gives, if any.
+    pub charset: Option<String>,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// Fetches pages from the open web on behalf of the model, which means fetching URLs nobody has vetted.
+/// It refuses anything that resolves to a private, loopback, link-local or otherwise non-public address,
+/// checking again on every redirect, and it connects to exactly the address it checked.
+/// It also refuses domains the deployment has ruled out, content it can't use, and bodies that are too large.
+/// Clones share their limits on how often each host is fetched from.
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    user_agent: String,
+    max_body_bytes: usize,
+    allowed_domains: Vec<String>,
+    denied_domains: Vec<String>,
+    politeness: Arc<Politeness>,
+}
+
+impl Fetcher {
+    pub fn new(user_agent: impl Into<String>, max_body_bytes: usize) -> Self {
+        Self {
+            user_agent: user_agent.into(),
+            max_body_bytes,
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            politeness: Arc::new(Politeness::new(Semaphore::MAX_PERMITS, Duration::ZERO)),
+        }
+    }
+
+    /// Makes at most `max_concurrent` requests to any one host at once, starting each at least `min_delay` after the one before.
+    /// Without this, hosts are fetched from as often as asked.
+    pub fn with_politeness(mut self, max_concurrent: usize, min_delay: Duration) -> Self {
+        self.politeness = Arc::new(Politeness::new(max_concurrent, min_delay));
+        self
+    }
+
+    /// Only fetches from these domains and their subdomains. If empty, which is the default, any domain not denied is fine.
+    pub fn with_allowed_domains(mut self, domains: &[String]) -> Self {
+        self.allowed_domains = normalize_domains(domains);
+        self
+    }
+
+    /// Never fetches from these domains or their subdomains, even if they are also allowed.
+    pub fn with_denied_domains(mut self, domains: &[String]) -> Self {
+        self.denied_domains = normalize_domains(domains);
+        self
+    }
+
+    /// The name robots.txt files know this fetcher by: its user agent's product token, e.g. `rainchain`.
+    pub fn product_token(&self) -> &str {
+        self.user_agent
+            .split(|c: char| c == '/' || c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// Spaces requests to `host` at least `delay` apart, as its robots.txt asks.
+    pub fn set_crawl_delay(&self, host: &str, delay: Duration) {
+        self.politeness.set_crawl_delay(host, delay);
+    }
+
+    /// Fetches `url`, refusing any response that isn't one of `content_types`.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        content_types: &[&str],
+    ) -> Result<Fetched, Box<dyn Error + Send + Sync>> {
+        self.fetch_url(Url::parse(url)?, content_types, true).await
+    }
+
+    /// Fetches the robots.txt of the site `url` is on.
+    /// It is fetched whenever its page is, so it doesn't count towards the delay between requests to the site.
+    pub async fn fetch_robots_txt(
+        &self,
+        url: &Url,
+    ) -> Result<Fetched, Box<dyn Error + Send + Sync>> {
+        self.fetch_url(url.join("/robots.txt")?, ROBOTS_TXT_CONTENT_TYPES, false)
+            .await
+    }
+
+    async fn fetch_url(
+        &self,
+        mut url: Url,
+        content_types: &[&str],
+        paced: bool,
+    ) -> Result<Fetched, Box<dyn Error + Send + Sync>> {
+        for _ in 0..=MAX_REDIRECTS {
+            let client = self.client_for(&url).await?;
+
+            // Held until the body has been read:
+            let _permit = self
+                .politeness
+                .wait_turn(url.host_str().unwrap_or_default(), paced)
+                .await;
+
+            let response = client
+                .get(url.clone())
+                .header(USER_AGENT, &self.user_agent)
+                .header(ACCEPT, content_types.join(", "))
+                .send()
+                .await?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .ok_or_else(|| format!("Redirect from {url} has no Location"))?
+                    .to_str()?;
+
+                let next = url.join(location)?;
+                debug!("Following redirect from {url} to {next}");
+                url = next;
+                continue;
+            }
+
+            let response = response.error_for_status()?;
+
+            let content_type_header = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+
+            let content_type = media_type(content_type_header);
+            let charset = charset(content_type_header);
+
+            if !content_types.contains(&content_type.as_str()) {
+                return Err(
+                    format!("Refusing {url}: unsupported content type '{content_type}'").into(),
+                );
+            }
+
+            let headers = response.headers().clone();
+            let body = self.read_body(response).await?;
+
+            return Ok(Fetched {
+                url,
+                content_type,
+                charset,
+                headers,
+                body,
+            });
+        }
+
+        Err(format!("Gave up on {url} after {MAX_REDIRECTS} redirects").into())
+    }
+
+    /// A client that may only connect to `url`'s host at an address that passed the checks.
+    /// Pinning the address stops the host from resolving to somewhere else between checking and connecting.
+    async fn client_for(&self, url: &Url) -> Result<reqwest::Client, Box<dyn Error + Send + Sync>> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("Refusing {url}: only http and https are fetched").into());
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("Refusing {url}: it has no host"))?
+            .to_ascii_lowercase();
+
+        self.check_domain(&host)
+            .map_err(|reason| format!("Refusing {url}: {reason}"))?;
+
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| format!("Refusing {url}: it has no port"))?;
+
+        let builder = reqwest::ClientBuilder::new()
+            .timeout(FETCH_TIMEOUT)
+            // A proxy would resolve the host itself, so could connect somewhere that wasn't checked:
+            .no_proxy()
+            // Every hop is checked here first, so redirects are followed by hand:
+            .redirect(redirect::Policy::none());
+
+        // IPv6 hosts come bracketed:
+        let literal_ip = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok();
+
+        let builder = if let Some(ip) = literal_ip {
+            if !is_public(ip) {
+                return Err(format!("Refusing {url}: {ip} is not a public address").into());
+            }
+
+            builder
+        } else {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .collect();
+
+            // Any one of them may be the one connected to, so all of them must be public:
+            if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+                return Err(format!("Refusing {url}: {host} resolves to {}", address.ip()).into());
+            }
+
+            let address = addresses
+                .first()
+                .ok_or_else(|| format!("Refusing {url}: {host} did not resolve"))?;
+
+            trace!("Resolved {host} to {address}");
+            builder.resolve(&host, *address)
+        };
+
+        Ok(builder.build()?)
+    }
+
+    fn check_domain(&self, host: &str) -> Result<(), String> {
+        if let Some(domain) = self
+            .denied_domains
+            .iter()
+            .find(|domain| is_within(host, domain))
+        {
+            return Err(format!("{domain} is denied"));
+        }
+
+        if !self.allowed_domains.is_empty()
+            && !self
+                .allowed_domains
+                .iter()
+                .any(|domain| is_within(host, domain))
+        {
+            return Err(format!("{host} is not an allowed domain"));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the body of `response`, failing as soon as it is known to be larger than allowed.
+    async fn read_body(
+        &self,
+        mut response: reqwest::Response,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let url = response.url().clone();
+        let too_large = || format!("Refusing {url}: body is over {} bytes", self.max_body_bytes);
+
+        let declared_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+
+        if declared_length.is_some_and(|length| length > self.max_body_bytes) {
+            return Err(too_large().into());
+        }
+
+        let mut body = Vec::with_capacity(declared_length.unwrap_or_default());
+
+        // The declared length may be missing, or a lie:
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > self.max_body_bytes {
+                return Err(too_large().into());
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(body)
+    }
+}
+
+/// The media type of a `Content-Type` header value, lowercased and without parameters such as the charset.
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// The `charset` parameter of a `Content-Type` header value, if it has one.
+fn charset(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_owned())
+    })
+}
+
+/// True if `host` is `domain` or one of its subdomains.
+fn is_within(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+fn normalize_domains(domains: &[String]) -> Vec<String> {
+    domains
+        .iter()
+        .map(|domain| domain.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+/// True if `ip` is reachable on the public internet, and so is fair game for fetching.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            // Traffic to these ends up at the IPv4 address inside them:
+            if let Some(ip) = embedded_ipv4(ip) {
+                return is_public_v4(ip);
+            }
+
+            is_public_v6(ip)
+        }
+    }
+}
+
+/// The IPv4 address that an IPv6 address stands in for, if it is one of the kinds that does.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let octets = ip.octets();
+    let last_32_bits = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+
+    match segments {
+        // IPv4-mapped, ::ffff:a.b.c.d, and NAT64, 64:ff9b::a.b.c.d and the local-use 64:ff9b:1::/48:
+        [0, 0, 0, 0, 0, 0xffff, _, _]
+        | [0x64, 0xff9b, 0, 0, 0, 0, _, _]
+        | [0x64, 0xff9b, 1, ..] => Some(last_32_bits),
+        // IPv4-compatible, ::a.b.c.d, deprecated but still understood by some stacks.
+        // Loopback and unspecified are left to the IPv6 checks:
+        [0, 0, 0, 0, 0, 0, _, _] if !ip.is_loopback() && !ip.is_unspecified() => Some(last_32_bits),
+        // 6to4, 2002:aabb:ccdd::/48:
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // "This network":
+        || a == 0
+        // Carrier-grade NAT, shared address space:
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments:
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking:
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved for future use:
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local:
+        || (first & 0xfe00) == 0xfc00
+        // Link-local:
+        || (first & 0xffc0) == 0xfe80
+        // Site-local, deprecated but still routed privately:
+        || (first & 0xffc0) == 0xfec0
+        // Documentation:
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_private_ipv4_addresses_inside_ipv6_ones() {
+        for private in [
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::192.168.1.1",
+            "64:ff9b:1::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{private}");
+        }
+
+        for public in [
+            "::ffff:8.8.8.8",
+            "64:ff9b::1.1.1.1",
+            "2002:808:808::",
+            "2606:4700::1111",
+        ] {
+            assert!(is_public(public.parse().unwrap()), "{public}");
+        }
+    }
+}
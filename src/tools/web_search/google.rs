@@ -0,0 +1,70 @@
+use std::{error::Error, time::Duration};
+
+use async_trait::async_trait;
+use log::debug;
+use serde::Deserialize;
+
+use super::{SearchProvider, SearchResult};
+
+/// Searches with the Google Custom Search JSON API.
+pub struct GoogleSearch {
+    api_key: String,
+    cx: String,
+}
+
+impl GoogleSearch {
+    pub fn new(api_key: impl Into<String>, cx: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            cx: cx.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl SearchProvider for GoogleSearch {
+    fn name(&self) -> &'static str {
+        "google"
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<SearchResult>, Box<dyn Error + Send + Sync>> {
+        debug!("Searching Google for '{query}'");
+
+        let response = reqwest::Client::new()
+            .get("https://www.googleapis.com/customsearch/v1")
+            .query(&[("cx", self.cx.as_str()), ("q", query)])
+            // Not the `key` query parameter, which would put the key in the url, and so in the errors that quote it:
+            .header("X-Goog-Api-Key", &self.api_key)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Response>()
+            .await?;
+
+        Ok(response
+            .items
+            .into_iter()
+            .map(|item| SearchResult {
+                title: item.title,
+                link: item.link,
+                snippet: item.snippet,
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    // Missing entirely when nothing matched:
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    title: String,
+    link: String,
+    #[serde(default)]
+    snippet: String,
+}
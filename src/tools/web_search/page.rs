@@ -0,0 +1,163 @@
+use kuchiki::NodeRef;
+use serde::{Deserialize, Serialize};
+
+/// Elements whose text is taken as one block. Anything nested inside another of these is part of that block.
+const BLOCK_SELECTOR: &str = "h1, h2, h3, h4, h5, h6, p, li, pre, blockquote, td, dd, dt";
+
+/// The readable content of a web page, in reading order, with its headings kept apart from its text.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Page {
+    pub title: Option<String>,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Block {
+    /// A heading, with its level from 1 (`h1`) to 6 (`h6`).
+    Heading(u8, String),
+    Paragraph(String),
+}
+
+impl Page {
+    /// Collects the blocks of the article `root`, as extracted by readability.
+    /// If it has no recognizable block elements, all its text becomes one paragraph.
+    pub fn from_article(root: &NodeRef, title: Option<String>) -> Self {
+        let mut blocks = Vec::new();
+
+        if let Ok(elements) = root.select(BLOCK_SELECTOR) {
+            for element in elements {
+                let node = element.as_node();
+
+                let is_nested = node
+                    .ancestors()
+                    .filter_map(NodeRef::into_element_ref)
+                    .any(|ancestor| is_block_element(&ancestor.name.local));
+
+                if is_nested {
+                    continue;
+                }
+
+                let text = collapse_whitespace(&node.text_contents());
+                if text.is_empty() {
+                    continue;
+                }
+
+                match heading_level(&element.name.local) {
+                    Some(level) => blocks.push(Block::Heading(level, text)),
+                    None => blocks.push(Block::Paragraph(text)),
+                }
+            }
+        }
+
+        if blocks.is_empty() {
+            let text = collapse_whitespace(&root.text_contents());
+            if !text.is_empty() {
+                blocks.push(Block::Paragraph(text));
+            }
+        }
+
+        Self {
+            title: title.map(|title| collapse_whitespace(&title)),
+            blocks,
+        }
+    }
+
+    /// Plain text, such as a text file or the text of a PDF, whose paragraphs are separated by blank lines.
+    /// Line breaks within a paragraph are taken to be wrapping.
+    pub fn from_text(text: &str, title: Option<String>) -> Self {
+        Self {
+            title,
+            blocks: text_blocks(text, false),
+        }
+    }
+
+    /// Markdown, keeping its `#` headings as headings. Everything else is taken as plain text.
+    pub fn from_markdown(text: &str, title: Option<String>) -> Self {
+        Self {
+            title,
+            blocks: text_blocks(text, true),
+        }
+    }
+
+    /// The length in bytes of all the page's text.
+    pub fn text_len(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|block| match block {
+                Block::Heading(_, text) | Block::Paragraph(text) => text.len(),
+            })
+            .sum()
+    }
+}
+
+fn text_blocks(text: &str, markdown: bool) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut paragraph = String::new();
+
+    for line in text.lines() {
+        if let Some((level, heading)) = markdown.then(|| markdown_heading(line)).flatten() {
+            end_paragraph(&mut paragraph, &mut blocks);
+            blocks.push(Block::Heading(level, heading));
+        } else if line.trim().is_empty() {
+            end_paragraph(&mut paragraph, &mut blocks);
+        } else {
+            paragraph.push_str(line);
+            paragraph.push('\n');
+        }
+    }
+
+    end_paragraph(&mut paragraph, &mut blocks);
+
+    blocks
+}
+
+/// Ends the paragraph collected so far, adding it to `blocks` unless it is empty.
+fn end_paragraph(paragraph: &mut String, blocks: &mut Vec<Block>) {
+    let text = collapse_whitespace(paragraph);
+    if !text.is_empty() {
+        blocks.push(Block::Paragraph(text));
+    }
+    paragraph.clear();
+}
+
+/// The level and text of `line`, if it is a Markdown heading such as `## Installation`.
+fn markdown_heading(line: &str) -> Option<(u8, String)> {
+    let line = line.trim_start();
+    let level = line.chars().take_while(|&c| c == '#').count();
+
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    // Closing hashes are decoration:
+    let text = collapse_whitespace(rest.trim().trim_end_matches('#'));
+
+    (!text.is_empty()).then(|| (u8::try_from(level).unwrap_or(6), text))
+}
+
+fn heading_level(name: &str) -> Option<u8> {
+    match name {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
+}
+
+fn is_block_element(name: &str) -> bool {
+    BLOCK_SELECTOR
+        .split(", ")
+        .any(|block_name| block_name == name)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::trace;
+use tokio::{
+    sync::{Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Hosts remembered before those no longer in use are forgotten.
+const MAX_REMEMBERED_HOSTS: usize = 1024;
+
+/// Keeps requests to any one host few and far between, however many sessions are searching at once,
+/// so that sites don't mistake us for an attack and block us.
+#[derive(Debug)]
+pub struct Politeness {
+    max_concurrent: usize,
+    min_delay: Duration,
+    hosts: Mutex<HashMap<String, Arc<HostState>>>,
+}
+
+#[derive(Debug)]
+struct HostState {
+    permits: Arc<Semaphore>,
+
+    /// When the next request to the host may start.
+    next_start: AsyncMutex<Instant>,
+
+    /// A longer delay than the default, if the host asked for one in its robots.txt.
+    crawl_delay: Mutex<Option<Duration>>,
+}
+
+impl Politeness {
+    /// At most `max_concurrent` requests are made to a host at once, and each starts at least `min_delay` after the one before.
+    pub fn new(max_concurrent: usize, min_delay: Duration) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+            min_delay,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a request to `host` may start. The request counts against the host's limit until the permit is dropped.
+    /// Requests that are `paced` also push back the start of the next request to the host.
+    pub async fn wait_turn(&self, host: &str, paced: bool) -> OwnedSemaphorePermit {
+        let state = self.host(host);
+
+        let permit = state
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Host semaphores are never closed");
+
+        if paced {
+            let delay = state
+                .crawl_delay
+                .lock()
+                .unwrap()
+                .map_or(self.min_delay, |crawl_delay| {
+                    crawl_delay.max(self.min_delay)
+                });
+
+            let mut next_start = state.next_start.lock().await;
+
+            if *next_start > Instant::now() {
+                trace!(
+                    "Waiting {:?} before fetching from {host}",
+                    *next_start - Instant::now()
+                );
+                tokio::time::sleep_until(*next_start).await;
+            }
+
+            *next_start = Instant::now() + delay;
+        }
+
+        permit
+    }
+
+    /// Spaces requests to `host` at least `delay` apart, if that is longer than the default.
+    pub fn set_crawl_delay(&self, host: &str, delay: Duration) {
+        *self.host(host).crawl_delay.lock().unwrap() = Some(delay);
+    }
+
+    fn host(&self, host: &str) -> Arc<HostState> {
+        let mut hosts = self.hosts.lock().unwrap();
+
+        if hosts.len() >= MAX_REMEMBERED_HOSTS && !hosts.contains_key(host) {
+            // Hosts nobody is fetching from, and that may be fetched from again right away, have nothing worth keeping:
+            hosts.retain(|_, state| {
+                Arc::strong_count(state) > 1
+                    || state
+                        .next_start
+                        .try_lock()
+                        .map_or(true, |next_start| *next_start > Instant::now())
+            });
+        }
+
+        hosts
+            .entry(host.to_owned())
+            .or_insert_with(|| {
+                Arc::new(HostState {
+                    permits: Arc::new(Semaphore::new(self.max_concurrent)),
+                    next_start: AsyncMutex::new(Instant::now()),
+                    crawl_delay: Mutex::new(None),
+                })
+            })
+            .clone()
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use ordered_float::OrderedFloat;
+
+/// BM25 term frequency saturation: how quickly repeating a term stops adding to the score.
+const BM25_K1: f32 = 1.2;
+
+/// BM25 length normalization: how much longer-than-average passages are penalized.
+const BM25_B: f32 = 0.75;
+
+/// Reciprocal rank fusion's damping constant. Larger values flatten the difference between top ranks.
+const RRF_K: f32 = 60.0;
+
+/// Scores passages against a query by exact term overlap, with Okapi BM25.
+/// Catches what embeddings blur over, such as product names, version numbers and figures.
+pub struct Bm25 {
+    documents: Vec<HashMap<String, usize>>,
+    lengths: Vec<usize>,
+    average_length: f32,
+    document_frequencies: HashMap<String, usize>,
+}
+
+impl Bm25 {
+    pub fn new(corpus: &[String]) -> Self {
+        let documents: Vec<HashMap<String, usize>> = corpus
+            .iter()
+            .map(|document| {
+                let mut frequencies = HashMap::new();
+                for term in tokenize(document) {
+                    *frequencies.entry(term).or_insert(0) += 1;
+                }
+                frequencies
+            })
+            .collect();
+
+        let lengths: Vec<usize> = documents.iter().map(|terms| terms.values().sum()).collect();
+
+        #[allow(clippy::cast_precision_loss)]
+        let average_length = if lengths.is_empty() {
+            0.0
+        } else {
+            lengths.iter().sum::<usize>() as f32 / lengths.len() as f32
+        };
+
+        let mut document_frequencies = HashMap::new();
+        for terms in &documents {
+            for term in terms.keys() {
+                *document_frequencies.entry(term.clone()).or_insert(0) += 1;
+            }
+        }
+
+        Self {
+            documents,
+            lengths,
+            average_length,
+            document_frequencies,
+        }
+    }
+
+    /// The score of every passage in the corpus for `query`, in corpus order. Higher is better; zero means no terms in common.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn scores(&self, query: &str) -> Vec<f32> {
+        let query_terms = tokenize(query);
+        let document_count = self.documents.len() as f32;
+
+        self.documents
+            .iter()
+            .zip(&self.lengths)
+            .map(|(terms, &length)| {
+                query_terms
+                    .iter()
+                    .filter_map(|term| {
+                        let frequency = *terms.get(term)? as f32;
+                        let containing = self.document_frequencies[term] as f32;
+
+                        let idf =
+                            ((document_count - containing + 0.5) / (containing + 0.5) + 1.0).ln();
+                        let length_norm =
+                            1.0 - BM25_B + BM25_B * length as f32 / self.average_length.max(1.0);
+
+                        Some(
+                            idf * frequency * (BM25_K1 + 1.0) / (frequency + BM25_K1 * length_norm),
+                        )
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+/// Lowercased words, keeping the dots, dashes and underscores inside them so that
+/// `v1.2.3`, `gpt-4` and `snake_case` stay whole.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || matches!(c, '.' | '-' | '_')))
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// How semantic and lexical scores are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FusionMethod {
+    /// Combines each passage's rank in the two orderings, ignoring the scores themselves.
+    /// Robust to the two scores having very different scales.
+    ReciprocalRank,
+
+    /// Combines the scores themselves, each first rescaled to between 0 and 1.
+    Weighted,
+}
+
+/// Ranks passages by both meaning and exact wording.
+#[derive(Debug, Clone, Copy)]
+pub struct HybridRanker {
+    method: FusionMethod,
+    lexical_weight: f32,
+}
+
+impl HybridRanker {
+    /// `lexical_weight` is the share, between 0 and 1, that the lexical ranking gets. The semantic ranking gets the rest.
+    pub fn new(method: FusionMethod, lexical_weight: f32) -> Self {
+        Self {
+            method,
+            lexical_weight: lexical_weight.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Combines per-passage `semantic` and `lexical` scores, given in the same order, into one score per passage.
+    pub fn fuse(self, semantic: &[f32], lexical: &[f32]) -> Vec<f32> {
+        let (semantic, lexical) = match self.method {
+            FusionMethod::ReciprocalRank => {
+                // Passages sharing no terms with the query weren't found lexically at all, so get no rank:
+                let lexical_ranks = reciprocal_ranks(lexical)
+                    .into_iter()
+                    .zip(lexical)
+                    .map(|(rank, &score)| if score > 0.0 { rank } else { 0.0 })
+                    .collect();
+
+                (reciprocal_ranks(semantic), lexical_ranks)
+            }
+            FusionMethod::Weighted => (min_max_normalize(semantic), min_max_normalize(lexical)),
+        };
+
+        semantic
+            .iter()
+            .zip(&lexical)
+            .map(|(semantic, lexical)| {
+                (1.0 - self.lexical_weight) * semantic + self.lexical_weight * lexical
+            })
+            .collect()
+    }
+}
+
+/// `1 / (k + rank)` for each score, where the best score has rank 1.
+#[allow(clippy::cast_precision_loss)]
+fn reciprocal_ranks(scores: &[f32]) -> Vec<f32> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by_key(|&index| -OrderedFloat(scores[index]));
+
+    let mut ranks = vec![0.0; scores.len()];
+    for (rank, index) in order.into_iter().enumerate() {
+        ranks[index] = 1.0 / (RRF_K + rank as f32 + 1.0);
+    }
+
+    ranks
+}
+
+/// Rescales `scores` to between 0 and 1, keeping their order. All zero if they are all the same.
+fn min_max_normalize(scores: &[f32]) -> Vec<f32> {
+    let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+    if max - min <= f32::EPSILON {
+        return vec![0.0; scores.len()];
+    }
+
+    scores
+        .iter()
+        .map(|score| (score - min) / (max - min))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Passages about a release, all close in meaning, of which only one is about the version asked for.
+    const CORPUS: &[&str] = &[
+        "An asynchronous runtime for Rust, providing tasks, timers, channels and non-blocking I/O.",
+        "Release notes for tokio 1.37.0: JoinSet gains try_join_next, and some docs are fixed.",
+        "tokio 1.38.0 adds task::id, and stabilizes the cooperative scheduling budget API.",
+        "How to write asynchronous code in Rust with futures and async/await.",
+        "Python's asyncio event loop runs coroutines and schedules callbacks.",
+    ];
+
+    const QUERY: &str = "what changed in tokio 1.38.0";
+
+    /// Stand-ins for the passages' cosine similarity to the query, as an embedding model would
+    /// give them: the general passages look the most alike, and the version number barely registers.
+    const SEMANTIC: &[f32] = &[0.83, 0.81, 0.78, 0.76, 0.61];
+
+    const ANSWER: usize = 2;
+
+    fn best(scores: &[f32]) -> usize {
+        (0..scores.len())
+            .max_by_key(|&index| OrderedFloat(scores[index]))
+            .unwrap()
+    }
+
+    #[test]
+    fn hybrid_ranking_finds_the_exact_match_that_embeddings_alone_miss() {
+        let corpus: Vec<String> = CORPUS.iter().map(|&passage| passage.to_owned()).collect();
+        let lexical = Bm25::new(&corpus).scores(QUERY);
+
+        assert_eq!(best(&lexical), ANSWER);
+        assert_ne!(best(SEMANTIC), ANSWER);
+
+        for method in [FusionMethod::ReciprocalRank, FusionMethod::Weighted] {
+            let fused = HybridRanker::new(method, 0.5).fuse(SEMANTIC, &lexical);
+            assert_eq!(best(&fused), ANSWER, "{method:?}: {fused:?}");
+        }
+    }
+
+    #[test]
+    fn keeps_versions_and_identifiers_whole() {
+        assert_eq!(
+            tokenize("Is gpt-4 in v1.2.3, or snake_case?"),
+            ["is", "gpt-4", "in", "v1.2.3", "or", "snake_case"]
+        );
+    }
+}
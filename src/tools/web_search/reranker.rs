@@ -0,0 +1,123 @@
+use futures::future;
+use log::{debug, warn};
+
+use crate::{
+    load_prompt_text,
+    model_client::{GuidanceRequestBuilder, ModelClient, RerankRequest},
+};
+
+/// The grades offered to the model, from least to most useful, as described in the grading prompt.
+const GRADES: &[&str] = &["0", "1", "2", "3"];
+
+/// How the re-ranker scores passages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RerankMethod {
+    /// Asks the model to grade each passage, with a guidance `select` over relevance grades.
+    Grades,
+
+    /// Sends all passages to the backend's cross-encoder in one request.
+    CrossEncoder,
+}
+
+/// A second, slower and more careful pass over the best-ranked passages, reading each
+/// one against the question rather than comparing embeddings.
+#[derive(Debug, Clone, Copy)]
+pub struct Reranker {
+    method: RerankMethod,
+    candidates: usize,
+}
+
+impl Reranker {
+    /// Re-ranks at most `candidates` passages at a time.
+    pub fn new(method: RerankMethod, candidates: usize) -> Self {
+        Self { method, candidates }
+    }
+
+    pub fn candidates(&self) -> usize {
+        self.candidates
+    }
+
+    /// A relevance score for each of `passages`, in order, higher being more useful for answering `question`.
+    /// `None` if they could not be scored, in which case the first-stage ranking should be kept.
+    pub async fn score(
+        &self,
+        question: &str,
+        passages: &[&str],
+        model_client: &(dyn ModelClient + Send + Sync),
+    ) -> Option<Vec<f32>> {
+        debug!(
+            "Re-ranking {} passages with {:?}",
+            passages.len(),
+            self.method
+        );
+
+        match self.method {
+            RerankMethod::Grades => {
+                let grades = passages
+                    .iter()
+                    .map(|passage| grade(question, passage, model_client));
+
+                Some(future::join_all(grades).await)
+            }
+            RerankMethod::CrossEncoder => {
+                let request = RerankRequest {
+                    query: question.to_owned(),
+                    documents: passages.iter().map(|&passage| passage.to_owned()).collect(),
+                };
+
+                match model_client.request_rerank(&request).await {
+                    Ok(response) => Some(response.scores),
+                    Err(e) => {
+                        warn!("Re-ranking failed, keeping the first-stage ranking: {e}");
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The model's expected grade for `passage`: each grade weighted by its probability.
+/// Weighing every grade, rather than taking the one selected, separates passages that got the same grade.
+async fn grade(
+    question: &str,
+    passage: &str,
+    model_client: &(dyn ModelClient + Send + Sync),
+) -> f32 {
+    let request = GuidanceRequestBuilder::new(load_prompt_text("rerank_grade.txt"))
+        .with_parameter("question", question)
+        .with_parameter("passage", passage)
+        .with_parameter_list("grades", GRADES)
+        .build();
+
+    let response = model_client.request_guidance(&request).await;
+
+    if let Some(logprobs) = response.logprobs("grade_logprobs") {
+        let grades: Vec<(f32, f32)> = logprobs
+            .iter()
+            .filter_map(|(grade, &logprob)| Some((grade.trim().parse::<f32>().ok()?, logprob)))
+            .collect();
+
+        let max = grades
+            .iter()
+            .map(|(_, logprob)| *logprob)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let total: f32 = grades
+            .iter()
+            .map(|(_, logprob)| (logprob - max).exp())
+            .sum();
+
+        if total > 0.0 {
+            return grades
+                .iter()
+                .map(|(grade, logprob)| grade * (logprob - max).exp() / total)
+                .sum();
+        }
+    }
+
+    // Without logprobs, the selected grade is all there is to go on:
+    response
+        .variable("grade")
+        .and_then(|grade| grade.trim().parse().ok())
+        .unwrap_or(0.0)
+}
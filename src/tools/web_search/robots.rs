@@ -0,0 +1,269 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// The rules of a site's `robots.txt`, as described by RFC 9309.
+/// A site without rules allows everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RobotsTxt {
+    groups: Vec<Group>,
+}
+
+/// The rules for a set of user agents.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Group {
+    /// Lowercased product tokens, or `*` for any crawler.
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl RobotsTxt {
+    pub fn parse(text: &str) -> Self {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut current = Group::default();
+        // Consecutive user-agent lines share the rules that follow them:
+        let mut taking_agents = false;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            let value = value.trim();
+
+            match key.trim().to_ascii_lowercase().as_str() {
+                "user-agent" => {
+                    if !taking_agents && !current.agents.is_empty() {
+                        groups.push(std::mem::take(&mut current));
+                    }
+
+                    current.agents.push(value.to_ascii_lowercase());
+                    taking_agents = true;
+                }
+                "allow" | "disallow" if !current.agents.is_empty() => {
+                    // An empty disallow rule disallows nothing:
+                    if !value.is_empty() {
+                        current.rules.push(Rule {
+                            allow: key.trim().eq_ignore_ascii_case("allow"),
+                            pattern: value.to_owned(),
+                        });
+                    }
+
+                    taking_agents = false;
+                }
+                "crawl-delay" if !current.agents.is_empty() => {
+                    current.crawl_delay = value.parse().ok().filter(|delay: &f64| *delay >= 0.0);
+                    taking_agents = false;
+                }
+                _ => {}
+            }
+        }
+
+        if !current.agents.is_empty() {
+            groups.push(current);
+        }
+
+        Self { groups }
+    }
+
+    /// Rules that forbid crawling anything, for when a site's rules can't be known.
+    pub fn disallow_all() -> Self {
+        Self {
+            groups: vec![Group {
+                agents: vec![String::from("*")],
+                rules: vec![Rule {
+                    allow: false,
+                    pattern: String::from("/"),
+                }],
+                crawl_delay: None,
+            }],
+        }
+    }
+
+    /// Whether the crawler named `product` may fetch `path`, which includes any query string.
+    /// The most specific matching rule decides, and allowing wins a tie.
+    pub fn is_allowed(&self, product: &str, path: &str) -> bool {
+        if path == "/robots.txt" {
+            return true;
+        }
+
+        self.groups_for(product)
+            .flat_map(|group| &group.rules)
+            .filter(|rule| matches(&rule.pattern, path))
+            .max_by_key(|rule| (rule.pattern.len(), rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+
+    /// How long the crawler named `product` is asked to wait between requests, if the site says.
+    pub fn crawl_delay(&self, product: &str) -> Option<Duration> {
+        self.groups_for(product)
+            .filter_map(|group| group.crawl_delay)
+            .reduce(f64::max)
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+    }
+
+    /// The groups naming `product`, or if there are none, the groups for any crawler.
+    fn groups_for<'a>(&'a self, product: &str) -> impl Iterator<Item = &'a Group> {
+        let product = product.to_ascii_lowercase();
+
+        let named = self
+            .groups
+            .iter()
+            .any(|group| group.agents.contains(&product));
+
+        let wanted = if named { product } else { String::from("*") };
+
+        self.groups
+            .iter()
+            .filter(move |group| group.agents.contains(&wanted))
+    }
+}
+
+/// Whether robots.txt `pattern` matches `path`. Patterns match from the start of the path,
+/// `*` matches any run of characters, and a trailing `$` anchors the pattern to the end of the path.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    let mut pieces = pattern.split('*');
+    let first = pieces.next().unwrap_or_default();
+
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+
+    let pieces: Vec<&str> = pieces.collect();
+
+    for (i, piece) in pieces.iter().enumerate() {
+        let is_last = i == pieces.len() - 1;
+
+        // The last piece of an anchored pattern must end the path, so it is matched as late as possible:
+        let position = if is_last && anchored {
+            rest.rfind(piece)
+        } else {
+            rest.find(piece)
+        };
+
+        let Some(position) = position else {
+            return false;
+        };
+
+        rest = &rest[position + piece.len()..];
+    }
+
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_group_naming_the_crawler() {
+        let robots_txt = RobotsTxt::parse(
+            "User-agent: *\n\
+             Disallow: /\n\
+             \n\
+             User-agent: OtherBot\n\
+             User-agent: RainBot # shares the rules below\n\
+             Disallow: /private\n",
+        );
+
+        assert!(robots_txt.is_allowed("rainbot", "/public"));
+        assert!(!robots_txt.is_allowed("RAINBOT", "/private/page"));
+        assert!(robots_txt.is_allowed("otherbot", "/public"));
+        assert!(!robots_txt.is_allowed("somebot", "/public"));
+        assert!(robots_txt.is_allowed("somebot", "/robots.txt"));
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        let robots_txt = RobotsTxt::parse("User-agent: *\nDisallow:\n");
+
+        assert!(robots_txt.is_allowed("rainbot", "/anything"));
+        assert!(RobotsTxt::default().is_allowed("rainbot", "/anything"));
+        assert!(!RobotsTxt::disallow_all().is_allowed("rainbot", "/anything"));
+    }
+
+    #[test]
+    fn matches_wildcards_and_anchors() {
+        assert!(matches("/private", "/private/page"));
+        assert!(!matches("/private", "/public/private"));
+        assert!(matches("/*.pdf", "/docs/report.pdf"));
+        assert!(matches("/*.pdf", "/docs/report.pdf?page=2"));
+        assert!(matches("/*.pdf$", "/docs/report.pdf"));
+        assert!(!matches("/*.pdf$", "/docs/report.pdf?page=2"));
+        assert!(matches("/*.pdf$", "/a.pdf/b.pdf"));
+        assert!(matches("/search$", "/search"));
+        assert!(!matches("/search$", "/search/more"));
+        assert!(matches("/*/edit*", "/page/edit?id=1"));
+        assert!(!matches("/*/edit", "/page"));
+    }
+
+    #[test]
+    fn lets_the_longest_match_decide() {
+        let robots_txt = RobotsTxt::parse(
+            "User-agent: *\n\
+             Disallow: /docs\n\
+             Allow: /docs/public\n\
+             Disallow: /docs/public/drafts\n",
+        );
+
+        assert!(!robots_txt.is_allowed("rainbot", "/docs/private"));
+        assert!(robots_txt.is_allowed("rainbot", "/docs/public/page"));
+        assert!(!robots_txt.is_allowed("rainbot", "/docs/public/drafts/page"));
+    }
+
+    #[test]
+    fn allows_when_rules_tie() {
+        let robots_txt = RobotsTxt::parse(
+            "User-agent: *\n\
+             Disallow: /page\n\
+             Allow: /page\n",
+        );
+
+        assert!(robots_txt.is_allowed("rainbot", "/page"));
+    }
+
+    #[test]
+    fn parses_crawl_delay() {
+        let robots_txt = RobotsTxt::parse(
+            "User-agent: *\n\
+             Crawl-delay: 2.5\n\
+             \n\
+             User-agent: RainBot\n\
+             Crawl-delay: soon\n\
+             \n\
+             User-agent: OtherBot\n\
+             Crawl-delay: -1\n\
+             \n\
+             User-agent: SlowBot\n\
+             Crawl-delay: 1\n\
+             \n\
+             User-agent: SlowBot\n\
+             Crawl-delay: 3\n",
+        );
+
+        assert_eq!(
+            robots_txt.crawl_delay("somebot"),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(robots_txt.crawl_delay("rainbot"), None);
+        assert_eq!(robots_txt.crawl_delay("otherbot"), None);
+        assert_eq!(
+            robots_txt.crawl_delay("slowbot"),
+            Some(Duration::from_secs(3))
+        );
+    }
+}
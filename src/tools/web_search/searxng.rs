@@ -0,0 +1,65 @@
+use std::{error::Error, time::Duration};
+
+use async_trait::async_trait;
+use log::debug;
+use serde::Deserialize;
+
+use super::{SearchProvider, SearchResult};
+
+/// Searches with a self-hosted `SearXNG` instance, which must have the `json` output format enabled.
+pub struct SearxngSearch {
+    base_url: String,
+}
+
+impl SearxngSearch {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl SearchProvider for SearxngSearch {
+    fn name(&self) -> &'static str {
+        "searxng"
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<SearchResult>, Box<dyn Error + Send + Sync>> {
+        debug!("Searching SearXNG at {} for '{query}'", self.base_url);
+
+        let response = reqwest::Client::new()
+            .get(format!("{}/search", self.base_url))
+            .query(&[("q", query), ("format", "json")])
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Response>()
+            .await?;
+
+        Ok(response
+            .results
+            .into_iter()
+            .map(|result| SearchResult {
+                title: result.title,
+                link: result.url,
+                snippet: result.content,
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    results: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    title: String,
+    url: String,
+    #[serde(default)]
+    content: String,
+}
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+
+use log::debug;
+use ordered_float::OrderedFloat;
+
+use super::cosine_similarity;
+
+/// Passages sharing at least this share of their word shingles are copies of the same text.
+/// Catches syndicated articles whose embeddings differ only because of the page title in their context.
+const DUPLICATE_SHINGLE_OVERLAP: f32 = 0.6;
+
+/// How many consecutive words make up a shingle.
+const SHINGLE_WORDS: usize = 3;
+
+/// Passages from the same page count as at least this similar to each other,
+/// so another page's view is preferred unless it is clearly less relevant.
+const SAME_SOURCE_SIMILARITY: f32 = 0.5;
+
+/// Picks the passages to show the model from the ranked candidates, by maximal marginal relevance:
+/// each pick is the passage that is most relevant while being least like the ones already picked.
+/// Near-duplicates of a picked passage are never picked.
+#[derive(Debug, Clone, Copy)]
+pub struct PassageSelector {
+    diversity: f32,
+    duplicate_similarity: f32,
+}
+
+/// A passage that may be picked, with everything needed to compare it to the others.
+struct Candidate<'a> {
+    index: usize,
+    relevance: f32,
+    source: usize,
+    embedding: &'a [f32],
+    shingles: HashSet<Vec<String>>,
+}
+
+impl PassageSelector {
+    /// `diversity`, between 0 and 1, is how much being unlike the passages already picked counts against relevance.
+    /// Zero picks purely by relevance.
+    /// Passages whose embeddings have a cosine similarity of at least `duplicate_similarity` are near-duplicates.
+    pub fn new(diversity: f32, duplicate_similarity: f32) -> Self {
+        Self {
+            diversity: diversity.clamp(0.0, 1.0),
+            duplicate_similarity,
+        }
+    }
+
+    /// Picks up to `count` of the `ranked` passages (section indices, best first), best first.
+    /// `sections`, `sources` and `embeddings` hold each section's text, search result and embedding, by index.
+    ///
+    /// Relevance comes from each passage's place in the ranking rather than its score,
+    /// as re-ranked passages are scored on a different scale from the rest.
+    pub fn select(
+        self,
+        ranked: &[usize],
+        sections: &[String],
+        sources: &[usize],
+        embeddings: &[Vec<f32>],
+        count: usize,
+    ) -> Vec<usize> {
+        let mut candidates: Vec<Candidate> = ranked
+            .iter()
+            .enumerate()
+            .map(|(position, &index)| Candidate {
+                index,
+                relevance: rank_relevance(position, ranked.len()),
+                source: sources[index],
+                embedding: &embeddings[index],
+                shingles: shingles(&sections[index]),
+            })
+            .collect();
+
+        let mut selected: Vec<Candidate> = Vec::new();
+
+        while selected.len() < count {
+            // Drop whatever merely repeats a passage already picked:
+            candidates.retain(|candidate| {
+                let duplicate = selected
+                    .iter()
+                    .find(|picked| self.is_duplicate(candidate, picked));
+
+                if let Some(picked) = duplicate {
+                    debug!(
+                        "Dropping section {}, a near-duplicate of section {}",
+                        candidate.index, picked.index
+                    );
+                }
+
+                duplicate.is_none()
+            });
+
+            // The first of any equally good candidates, so ties go to the better-ranked one:
+            let best = candidates
+                .iter()
+                .enumerate()
+                .map(|(position, candidate)| {
+                    let redundancy = selected
+                        .iter()
+                        .map(|picked| similarity(candidate, picked))
+                        .fold(0.0, f32::max);
+
+                    let score =
+                        (1.0 - self.diversity) * candidate.relevance - self.diversity * redundancy;
+
+                    (position, OrderedFloat(score))
+                })
+                .rev()
+                .max_by_key(|(_, score)| *score);
+
+            let Some((position, _)) = best else {
+                break;
+            };
+
+            selected.push(candidates.remove(position));
+        }
+
+        selected.into_iter().map(|picked| picked.index).collect()
+    }
+
+    fn is_duplicate(self, a: &Candidate, b: &Candidate) -> bool {
+        cosine_similarity(a.embedding, b.embedding) >= self.duplicate_similarity
+            || shingle_overlap(&a.shingles, &b.shingles) >= DUPLICATE_SHINGLE_OVERLAP
+    }
+}
+
+/// The relevance of the passage at `position` among `count` ranked ones: 1 for the best, falling evenly to 0 for the worst.
+#[allow(clippy::cast_precision_loss)]
+fn rank_relevance(position: usize, count: usize) -> f32 {
+    if count <= 1 {
+        return 1.0;
+    }
+
+    1.0 - position as f32 / (count - 1) as f32
+}
+
+/// How alike two passages are for the purposes of diversity, from their meaning and where they came from.
+fn similarity(a: &Candidate, b: &Candidate) -> f32 {
+    let similarity = cosine_similarity(a.embedding, b.embedding);
+
+    if a.source == b.source {
+        similarity.max(SAME_SOURCE_SIMILARITY)
+    } else {
+        similarity
+    }
+}
+
+/// Every run of `SHINGLE_WORDS` consecutive words in `text`, lowercased and without punctuation.
+fn shingles(text: &str) -> HashSet<Vec<String>> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    words
+        .windows(SHINGLE_WORDS.min(words.len().max(1)))
+        .map(<[String]>::to_vec)
+        .collect()
+}
+
+/// The Jaccard similarity of two sets of shingles.
+#[allow(clippy::cast_precision_loss)]
+fn shingle_overlap(a: &HashSet<Vec<String>>, b: &HashSet<Vec<String>>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+
+    a.intersection(b).count() as f32 / union as f32
+}
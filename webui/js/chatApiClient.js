@@ -1,10 +1,33 @@
 import { getContext } from "./script.js";
-import { addNewBotChatBubble, addNewSourceChatBubble, appendTextBotChatBubble } from "./ui.js";
+import { addNewBotChatBubble, addNewConfirmationChatBubble, addNewMemoryListChatBubble, addNewSourceChatBubble, addNewSourceListChatBubble, appendTextBotChatBubble } from "./ui.js";
 // const URI = "ws://archdesktop.local:5007/api/v1/stream";
 const URI = "ws://localhost:5007/api/v1/stream";
+const USER_TOKEN_KEY = "rainchain_user_token";
+// A secret identifying this browser across sessions, so the server can keep its memories separate from other users'.
+// Anyone holding it can see and forget those memories, so it is random and only ever sent to the server.
+function getUserToken() {
+    let userToken = localStorage.getItem(USER_TOKEN_KEY);
+    if (userToken === null) {
+        userToken = crypto.randomUUID();
+        localStorage.setItem(USER_TOKEN_KEY, userToken);
+    }
+    return userToken;
+}
 export function sendChat(message) {
     const request = {
-        message: message
+        message: message,
+        user_token: getUserToken()
+    };
+    const json = JSON.stringify(request);
+    const socket = getContext().socket;
+    socket === null || socket === void 0 ? void 0 : socket.send(json);
+}
+// Answers the agent's pending request to run an action that changes something, like a device in the home.
+export function sendConfirmation(approved) {
+    const request = {
+        message: approved ? "yes" : "no",
+        user_token: getUserToken(),
+        confirmed: approved
     };
     const json = JSON.stringify(request);
     const socket = getContext().socket;
@@ -29,7 +52,23 @@ export function openWebSocketConnection() {
     getContext().socket = socket;
 }
 function handleMessage(message) {
-    if (message.event == "ToolInfo") {
+    if (message.event == "Done") {
+        // The full response was already streamed to us piece by piece. Nothing to do here.
+        return;
+    }
+    else if (message.event == "ConfirmationRequest") {
+        addNewConfirmationChatBubble(message.text);
+        return;
+    }
+    else if (message.event == "MemoryList") {
+        addNewMemoryListChatBubble(JSON.parse(message.text));
+        return;
+    }
+    else if (message.event == "Sources") {
+        addNewSourceListChatBubble(JSON.parse(message.text));
+        return;
+    }
+    else if (message.event == "ToolInfo") {
         addNewSourceChatBubble(message.text);
         return;
     }
@@ -1,4 +1,4 @@
-import { sendChat } from "./chatApiClient.js";
+import { sendChat, sendConfirmation } from "./chatApiClient.js";
 
 function getInputElement(): HTMLInputElement {
     return document.getElementById("user-input") as HTMLInputElement;
@@ -81,6 +81,166 @@ export function addNewSourceChatBubble(text: string) {
     chatSection.appendChild(fragment);
 }
 
+export type Source = {
+    title: string,
+    url: string
+};
+
+// Only web links are made clickable, so following a source can never run script.
+function isWebUrl(url: string): boolean {
+    try {
+        const protocol = new URL(url).protocol;
+        return protocol === "http:" || protocol === "https:";
+    } catch {
+        return false;
+    }
+}
+
+function makeSourceLink(source: Source, text: string): HTMLElement {
+    if (!isWebUrl(source.url)) {
+        const span = document.createElement('span');
+        span.textContent = text;
+        return span;
+    }
+
+    const link = document.createElement('a');
+    link.href = source.url;
+    link.target = "_blank";
+    link.rel = "noopener noreferrer";
+    link.title = source.title;
+    link.textContent = text;
+
+    return link;
+}
+
+// Turns each "[n]" in the bubble's text into a link to the nth source.
+function linkCitations(bubble: HTMLDivElement, sources: Source[]) {
+    const walker = document.createTreeWalker(bubble, NodeFilter.SHOW_TEXT);
+    const textNodes: Text[] = [];
+
+    while (walker.nextNode()) {
+        textNodes.push(walker.currentNode as Text);
+    }
+
+    for (const node of textNodes) {
+        const parts = (node.textContent ?? "").split(/(\[\d+\])/);
+
+        if (parts.length === 1) {
+            continue;
+        }
+
+        const replacement = document.createDocumentFragment();
+
+        for (const part of parts) {
+            const citation = /^\[(\d+)\]$/.exec(part);
+            const source = citation?.[1] === undefined ? undefined : sources[Number(citation[1]) - 1];
+
+            if (source === undefined) {
+                replacement.appendChild(document.createTextNode(part));
+            } else {
+                const link = makeSourceLink(source, part);
+                link.classList.add("citation");
+                replacement.appendChild(link);
+            }
+        }
+
+        node.replaceWith(replacement);
+    }
+}
+
+// Links the citations in the response just given, and lists the sources they point to underneath it.
+export function addNewSourceListChatBubble(sources: Source[]) {
+    linkCitations(getLastBotChatBubble(), sources);
+
+    const chatSection = getChatMessagesSection();
+
+    const template = getTemplate("message-citations-template");
+    const fragment = template.content.cloneNode(true) as DocumentFragment;
+
+    const list = fragment.querySelector('ol') as HTMLOListElement;
+
+    for (const source of sources) {
+        const entry = document.createElement('li');
+        entry.appendChild(makeSourceLink(source, source.title || source.url));
+        list.appendChild(entry);
+    }
+
+    chatSection.appendChild(fragment);
+}
+
+export type MemoryListItem = {
+    id: string,
+    document: string,
+    timestamp: number | null,
+    source: string | null
+};
+
+export function addNewMemoryListChatBubble(items: MemoryListItem[]) {
+    const chatSection = getChatMessagesSection();
+
+    const template = getTemplate("message-memories-template");
+    const fragment = template.content.cloneNode(true) as DocumentFragment;
+
+    const list = fragment.querySelector('ul') as HTMLUListElement;
+
+    if (items.length === 0) {
+        const empty = document.createElement('li');
+        empty.textContent = "No memories found.";
+        list.appendChild(empty);
+    }
+
+    for (const item of items) {
+        const entry = document.createElement('li');
+
+        const text = document.createElement('span');
+        text.innerHTML = sanitizeAndPreserveNewlines(item.document);
+        entry.appendChild(text);
+
+        if (item.timestamp !== null) {
+            const date = document.createElement('small');
+            date.textContent = new Date(item.timestamp * 1000).toLocaleString();
+            entry.appendChild(date);
+        }
+
+        const forget = document.createElement('button');
+        forget.textContent = "Forget";
+        forget.onclick = () => {
+            sendChat("/forget " + item.id);
+            entry.remove();
+        };
+        entry.appendChild(forget);
+
+        list.appendChild(entry);
+    }
+
+    chatSection.appendChild(fragment);
+}
+
+export function addNewConfirmationChatBubble(text: string) {
+    const chatSection = getChatMessagesSection();
+
+    const template = getTemplate("message-confirmation-template");
+    const fragment = template.content.cloneNode(true) as DocumentFragment;
+
+    const content = fragment.querySelector('.message-content') as HTMLSpanElement;
+    content.innerHTML = sanitizeAndPreserveNewlines(text);
+
+    const buttons = Array.from(fragment.querySelectorAll('button'));
+
+    for (const button of buttons) {
+        button.onclick = () => {
+            sendConfirmation(button.dataset['approve'] === "true");
+
+            // Each request is answered only once:
+            for (const other of buttons) {
+                other.disabled = true;
+            }
+        };
+    }
+
+    chatSection.appendChild(fragment);
+}
+
 export function addNewBotChatBubble(text: string) {
     text = sanitizeAndPreserveNewlines(text);
     const chatSection = getChatMessagesSection();